@@ -1,13 +1,23 @@
+use color_eyre::eyre::WrapErr;
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 use std::process::{Output, Stdio};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing::instrument::Instrumented;
 use tracing::Instrument;
 
+use crate::progress::Progress;
+
 mod parsing {
-    use crate::diffing::{CompLog, ErrLog, Finds, Message, TraceLog, WarnLog};
+    use crate::diffing::{
+        strip_prefixes, CompLog, DiffOptions, ErrLog, LineStats, LogKey, Message, Position,
+        ResultLog, Side, TraceLog, WarnLog,
+    };
     use regex::Regex;
     use serde::{Deserialize, Serialize};
     use std::collections::HashMap;
@@ -15,12 +25,32 @@ mod parsing {
     use std::sync::LazyLock;
 
     #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
-    struct LogEntry {
+    pub struct LogEntry {
         action: String,
         file: Option<String>,
+        // Only `msg` actions have a level and message
+        #[serde(default)]
         level: i16,
+        #[serde(default)]
         msg: Message,
         raw_msg: Option<Message>,
+        /// Everything we don't model explicitly, like the `type` and `fields` of `result` actions
+        #[serde(flatten)]
+        fields: serde_json::Map<String, serde_json::Value>,
+    }
+
+    /// `type` of `result` actions carrying a line of build output
+    const RES_BUILD_LOG_LINE: u64 = 101;
+
+    /// Turn a `result` action into a message, so that it can be compared like the others
+    fn result_msg(mut entry: LogEntry) -> LogEntry {
+        let typ = entry.fields.get("type").and_then(serde_json::Value::as_u64);
+        let fields = entry.fields.remove("fields").unwrap_or_default();
+        entry.msg = match (typ, fields.get(0).and_then(serde_json::Value::as_str)) {
+            (Some(RES_BUILD_LOG_LINE), Some(line)) => format!("Build log: {}", line).into(),
+            (typ, _) => format!("Result {}: {}", typ.unwrap_or_default(), fields).into(),
+        };
+        entry
     }
 
     static DEP_FINDER_RE: LazyLock<Regex> = LazyLock::new(|| {
@@ -28,55 +58,106 @@ mod parsing {
     });
 
     fn simplify_msg(msg: Message) -> Message {
-        let m = DEP_FINDER_RE.captures(msg.as_str());
+        let m = DEP_FINDER_RE.captures(&msg);
         match m {
-            Some(name) => "Deprecated Feature: ".to_string() + name["feature_name"].as_ref(),
+            Some(name) => format!("Deprecated Feature: {}", &name["feature_name"]).into(),
             None => msg,
         }
     }
 
-    fn dedup_log(entries: Vec<LogEntry>, file: &Path) -> CompLog {
+    fn dedup_log(entries: Vec<LogEntry>, file: &Path, options: &DiffOptions) -> CompLog {
         // entries.into_iter().map(|le| {(le.raw_msg, le.file)}).into_group_map();
-        let mut hm: HashMap<Message, Finds> = HashMap::new();
+        let mut hm: CompLog = HashMap::new();
         let fp: String = file.to_str().map(|s| s.to_string()).unwrap();
         for entr in entries {
-            let key = entr.raw_msg.unwrap_or(entr.msg);
-            let key = simplify_msg(key);
-            hm.entry(key)
-                .or_insert(Default::default())
-                .positions
-                .insert(entr.file.unwrap_or(fp.clone()));
+            let pos_field = |name: &str| {
+                entr.fields
+                    .get(name)
+                    .and_then(serde_json::Value::as_u64)
+                    .and_then(|n| u32::try_from(n).ok())
+            };
+            let file = entr.file.unwrap_or(fp.clone());
+            let pos = Position {
+                line: pos_field("line"),
+                column: pos_field("column"),
+                file: strip_prefixes(Path::new(&file), &options.strip_prefix)
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            let raw = entr.raw_msg.unwrap_or(entr.msg);
+            let msg = simplify_msg(raw.clone());
+            let key = LogKey {
+                msg: msg.clone(),
+                file: (!options.dedup_across_files).then(|| pos.file.clone()),
+            };
+            let finds = hm.entry(key).or_insert(Default::default());
+            finds.positions.insert(pos);
+            if options.keep_raw_messages && raw != msg {
+                finds.raw.insert(raw);
+            }
         }
         hm
     }
 
-    pub fn split_stderr(stderr: String, file: &Path) -> (ErrLog, WarnLog, TraceLog) {
+    /// Parse the lines of `internal-json` stderr, without looking at what they mean.
+    /// Never panics, whatever the input. Lines that aren't `@nix` JSON are passed to
+    /// `on_error` together with the reason
+    pub fn parse_log_entries(
+        stderr: &str,
+        mut on_error: impl FnMut(&str, String),
+    ) -> Vec<LogEntry> {
+        stderr
+            .split('\n')
+            // Captured outputs may have been through a Windows machine, with `\r\n` line endings
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| match line.strip_prefix("@nix ") {
+                Some(json) => match serde_json::from_str::<LogEntry>(json) {
+                    Ok(entry) => Some(entry),
+                    Err(e) => {
+                        on_error(line, format!("error parsing json: {}", e));
+                        None
+                    }
+                },
+                None => {
+                    on_error(line, "not an `@nix` line".into());
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn split_stderr(
+        stderr: String,
+        side: Side,
+        file: &Path,
+        options: &DiffOptions,
+        stats: &LineStats,
+    ) -> (ErrLog, WarnLog, TraceLog, ResultLog) {
         let mut errmsgs: Vec<LogEntry> = vec![];
         let mut warnmsgs: Vec<LogEntry> = vec![];
         let mut tracemsgs: Vec<LogEntry> = vec![];
+        let mut results: Vec<LogEntry> = vec![];
         let mut logs: Vec<LogEntry> = vec![];
-        let re = Regex::new(r"\n").unwrap();
-        re.split(stderr.as_str()).for_each(|line| {
-            match line.get(0..4) {
-                Some("@nix") => {
-                    //throw away the @nix part, otherwise its invalid json
-                    let j = line.get(5..).unwrap();
-                    match serde_json::from_str::<LogEntry>(j) {
-                        Ok(v) => {
-                            if v.action != "msg" {
-                                todo!("new action type: {}", v.action);
-                            }
-                            logs.push(v)
-                        }
-                        Err(e) => tracing::error!("error parsing json: {}; {}", e, j),
-                    }
-                }
-                Some(t) => {
-                    todo!("new type: {}", t)
-                }
-                None => {}
-            }
+        let entries = parse_log_entries(&stderr, |line, reason| {
+            stats.record(side, false);
+            tracing::error!("{}; {}", reason, line);
         });
+        for entry in entries {
+            stats.record(side, true);
+            match entry.action.as_str() {
+                "msg" => logs.push(entry),
+                "result" => results.push(result_msg(entry)),
+                // Activity bookkeeping, carries no output of its own
+                "start" | "stop" => {}
+                // Actions of newer Nix versions aren't compared until they are modelled
+                _ => tracing::debug!(
+                    action = %entry.action,
+                    fields = ?entry.fields,
+                    "Ignoring unknown action"
+                ),
+            }
+        }
         for log in logs {
             if log.level == 0 {
                 errmsgs.push(log);
@@ -87,43 +168,820 @@ mod parsing {
             }
         }
         (
-            dedup_log(errmsgs, file),
-            dedup_log(warnmsgs, file),
-            dedup_log(tracemsgs, file),
+            dedup_log(errmsgs, file, options),
+            dedup_log(warnmsgs, file, options),
+            dedup_log(tracemsgs, file, options),
+            dedup_log(results, file, options),
         )
     }
 }
 
-pub type Message = String;
-pub type Position = String;
+/// Structural comparison of `nix-instantiate --parse` output, to tell which nodes of the AST
+/// differ instead of only that the whole text does
+mod ast {
+    use super::canonical::{find_close, skip_string, split_top_level};
+    use super::{AstChange, Diff};
+    use std::collections::HashMap;
+
+    /// Changes to record per file at most. Past that, the ASTs are hardly related anyway
+    const MAX_CHANGES: usize = 100;
+    /// Longest node text to record, longer ones are cut off
+    const MAX_NODE_TEXT: usize = 200;
+    /// Largest number of child pairs to align, beyond which children are compared by index
+    const MAX_ALIGNMENT: usize = 1 << 20;
+
+    /// An expression of the AST, borrowing the text it was parsed from
+    #[derive(PartialEq)]
+    enum Node<'s> {
+        /// A token without brackets, like an identifier, operator or string
+        Leaf(&'s str),
+        /// Bracketed tokens, or a run of tokens without brackets. A `{ … }` without bindings,
+        /// like lambda formals, is a group as well
+        Group {
+            text: &'s str,
+            open: Option<u8>,
+            children: Vec<Node<'s>>,
+        },
+        /// `{ … }` with bindings, by their left-hand side
+        Attrs {
+            text: &'s str,
+            bindings: Vec<(&'s str, Node<'s>)>,
+            rest: &'s str,
+        },
+    }
+
+    impl<'s> Node<'s> {
+        fn text(&self) -> &'s str {
+            match self {
+                Node::Leaf(text) | Node::Group { text, .. } | Node::Attrs { text, .. } => text,
+            }
+        }
+    }
+
+    /// The nodes that differ between the ASTs `a` and `b`
+    pub fn diff(a: &str, b: &str) -> Vec<AstChange> {
+        let mut changes = vec![];
+        diff_nodes(&parse(a), &parse(b), "$".to_string(), &mut changes);
+        changes
+    }
+
+    fn parse(s: &str) -> Node<'_> {
+        let s = s.trim();
+        let mut tokens = tokenize(s);
+        if tokens.len() == 1 {
+            tokens.remove(0)
+        } else {
+            Node::Group {
+                text: s,
+                open: None,
+                children: tokens,
+            }
+        }
+    }
+
+    fn tokenize(s: &str) -> Vec<Node<'_>> {
+        let bytes = s.as_bytes();
+        let mut tokens = vec![];
+        let mut i = 0;
+        while i < bytes.len() {
+            let start = i;
+            match bytes[i] {
+                c if c.is_ascii_whitespace() => i += 1,
+                open @ (b'(' | b'[' | b'{') => {
+                    let Some(end) = find_close(bytes, i) else {
+                        // Unbalanced, which the parsers don't print. Keep the rest as it is
+                        tokens.push(Node::Leaf(&s[start..]));
+                        break;
+                    };
+                    tokens.push(bracketed(&s[start..=end], open));
+                    i = end + 1;
+                }
+                _ => {
+                    while i < bytes.len()
+                        && !bytes[i].is_ascii_whitespace()
+                        && !matches!(bytes[i], b'(' | b'[' | b'{')
+                    {
+                        i = match bytes[i] {
+                            b'"' => skip_string(bytes, i),
+                            _ => i + 1,
+                        };
+                    }
+                    tokens.push(Node::Leaf(&s[start..i]));
+                }
+            }
+        }
+        tokens
+    }
+
+    fn bracketed(text: &str, open: u8) -> Node<'_> {
+        let inner = &text[1..text.len() - 1];
+        if open == b'{' {
+            let mut bindings = split_top_level(inner, b';');
+            let rest = bindings.pop().unwrap_or_default();
+            if !bindings.is_empty() {
+                return Node::Attrs {
+                    text,
+                    bindings: bindings.into_iter().map(binding).collect(),
+                    rest: rest.trim(),
+                };
+            }
+        }
+        Node::Group {
+            text,
+            open: Some(open),
+            children: tokenize(inner),
+        }
+    }
+
+    /// A binding by its left-hand side, like `a.b` of `a.b = 1`. `inherit`s are kept whole
+    fn binding(binding: &str) -> (&str, Node<'_>) {
+        let binding = binding.trim();
+        match split_top_level(binding, b'=').first() {
+            Some(name) if name.len() < binding.len() => {
+                (name.trim(), parse(&binding[name.len() + 1..]))
+            }
+            _ => (binding, Node::Leaf(binding)),
+        }
+    }
+
+    fn diff_nodes(a: &Node, b: &Node, path: String, changes: &mut Vec<AstChange>) {
+        if a == b || changes.len() >= MAX_CHANGES {
+            return;
+        }
+        match (a, b) {
+            (
+                Node::Attrs {
+                    bindings: bindings_a,
+                    rest: rest_a,
+                    ..
+                },
+                Node::Attrs {
+                    bindings: bindings_b,
+                    rest: rest_b,
+                    ..
+                },
+            ) => {
+                let by_name_a: HashMap<_, _> = bindings_a.iter().map(|(n, v)| (*n, v)).collect();
+                let by_name_b: HashMap<_, _> = bindings_b.iter().map(|(n, v)| (*n, v)).collect();
+                for (name, node) in bindings_a {
+                    let path = format!("{}.{}", path, name);
+                    match by_name_b.get(name) {
+                        Some(other) => diff_nodes(node, other, path, changes),
+                        None => push(changes, path, Some(node.text()), None),
+                    }
+                }
+                for (name, node) in bindings_b {
+                    if !by_name_a.contains_key(name) {
+                        let path = format!("{}.{}", path, name);
+                        push(changes, path, None, Some(node.text()));
+                    }
+                }
+                if rest_a != rest_b {
+                    push(changes, path, Some(rest_a), Some(rest_b));
+                }
+            }
+            (
+                Node::Group {
+                    open: open_a,
+                    children: children_a,
+                    ..
+                },
+                Node::Group {
+                    open: open_b,
+                    children: children_b,
+                    ..
+                },
+            ) if open_a == open_b => diff_children(children_a, children_b, &path, changes),
+            _ => push(changes, path, Some(a.text()), Some(b.text())),
+        }
+    }
+
+    /// Align the children of two groups at their longest common subsequence, so that an
+    /// inserted child doesn't shift all that follow, and compare the ones in between pairwise
+    fn diff_children(a: &[Node], b: &[Node], path: &str, changes: &mut Vec<AstChange>) {
+        if a.len().saturating_mul(b.len()) > MAX_ALIGNMENT {
+            let (gap_a, gap_b): (Vec<_>, Vec<_>) = ((0..a.len()).collect(), (0..b.len()).collect());
+            return diff_gap(a, b, &gap_a, &gap_b, path, changes);
+        }
+        // lcs[i][j] is the length of the longest common subsequence of a[i..] and b[j..]
+        let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i][j] = if a[i] == b[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        let (mut gap_a, mut gap_b) = (vec![], vec![]);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                diff_gap(a, b, &gap_a, &gap_b, path, changes);
+                gap_a.clear();
+                gap_b.clear();
+                i += 1;
+                j += 1;
+            } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+                gap_a.push(i);
+                i += 1;
+            } else {
+                gap_b.push(j);
+                j += 1;
+            }
+        }
+        diff_gap(a, b, &gap_a, &gap_b, path, changes);
+    }
+
+    /// Compare the unaligned children between two aligned ones pairwise, the leftover ones
+    /// were removed or added
+    fn diff_gap(
+        a: &[Node],
+        b: &[Node],
+        gap_a: &[usize],
+        gap_b: &[usize],
+        path: &str,
+        changes: &mut Vec<AstChange>,
+    ) {
+        for (k, &i) in gap_a.iter().enumerate() {
+            let path = format!("{}[{}]", path, i);
+            match gap_b.get(k) {
+                Some(&j) => diff_nodes(&a[i], &b[j], path, changes),
+                None => push(changes, path, Some(a[i].text()), None),
+            }
+        }
+        for &j in gap_b.iter().skip(gap_a.len()) {
+            push(changes, format!("{}[{}]", path, j), None, Some(b[j].text()));
+        }
+    }
+
+    fn push(changes: &mut Vec<AstChange>, path: String, a: Option<&str>, b: Option<&str>) {
+        if changes.len() >= MAX_CHANGES {
+            return;
+        }
+        changes.push(AstChange {
+            path,
+            node: Diff {
+                result_a: a.map(|text| shorten(text).into()),
+                result_b: b.map(|text| shorten(text).into()),
+            },
+        });
+    }
+
+    fn shorten(text: &str) -> String {
+        if text.len() <= MAX_NODE_TEXT {
+            return text.to_string();
+        }
+        let mut end = MAX_NODE_TEXT;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}…", &text[..end])
+    }
+}
+
+/// Canonicalization of `nix-instantiate --parse` output, so that harmless differences in the
+/// order of bindings don't count as a diff
+mod canonical {
+    /// Sort the bindings of all attribute sets. The result is only meant for comparisons.
+    pub fn canonicalize_ast(ast: &str) -> String {
+        let mut out = String::with_capacity(ast.len());
+        canonicalize_into(ast, &mut out);
+        out
+    }
+
+    fn canonicalize_into(s: &str, out: &mut String) {
+        let bytes = s.as_bytes();
+        let (mut i, mut copied) = (0, 0);
+        while i < bytes.len() {
+            match bytes[i] {
+                b'"' => i = skip_string(bytes, i),
+                b'{' => {
+                    let Some(end) = find_close(bytes, i) else {
+                        break;
+                    };
+                    out.push_str(&s[copied..i]);
+                    canonicalize_braces(&s[i + 1..end], out);
+                    i = end + 1;
+                    copied = i;
+                }
+                _ => i += 1,
+            }
+        }
+        out.push_str(&s[copied..]);
+    }
+
+    /// Canonicalize the inside of `{ … }`, which are either bindings or lambda formals
+    fn canonicalize_braces(inner: &str, out: &mut String) {
+        let mut bindings = split_top_level(inner, b';');
+        let rest = bindings.pop().unwrap_or_default();
+        if bindings.is_empty() {
+            out.push('{');
+            canonicalize_into(inner, out);
+            out.push('}');
+            return;
+        }
+        let mut bindings: Vec<String> = bindings
+            .into_iter()
+            .map(|binding| canonicalize_ast(binding.trim()))
+            .collect();
+        bindings.sort();
+        out.push_str("{ ");
+        for binding in bindings {
+            out.push_str(&binding);
+            out.push_str("; ");
+        }
+        canonicalize_into(rest.trim(), out);
+        out.push('}');
+    }
+
+    /// Split at `sep`, but not within strings or brackets
+    pub fn split_top_level(s: &str, sep: u8) -> Vec<&str> {
+        let bytes = s.as_bytes();
+        let mut parts = vec![];
+        let (mut i, mut start) = (0, 0);
+        while i < bytes.len() {
+            match bytes[i] {
+                b'"' => i = skip_string(bytes, i),
+                b'{' | b'(' | b'[' => i = find_close(bytes, i).map_or(bytes.len(), |end| end + 1),
+                c => {
+                    if c == sep {
+                        parts.push(&s[start..i]);
+                        start = i + 1;
+                    }
+                    i += 1;
+                }
+            }
+        }
+        parts.push(&s[start..]);
+        parts
+    }
+
+    /// Index after the end of the string starting at `start`
+    pub fn skip_string(bytes: &[u8], start: usize) -> usize {
+        let mut i = start + 1;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\\' => i += 2,
+                b'"' => return i + 1,
+                b'$' if bytes.get(i + 1) == Some(&b'{') => match find_close(bytes, i + 1) {
+                    Some(end) => i = end + 1,
+                    None => break,
+                },
+                _ => i += 1,
+            }
+        }
+        bytes.len()
+    }
+
+    /// Index of the bracket closing the one at `open`
+    pub fn find_close(bytes: &[u8], open: usize) -> Option<usize> {
+        let mut depth = 0usize;
+        let mut i = open;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'"' => {
+                    i = skip_string(bytes, i);
+                    continue;
+                }
+                b'{' | b'(' | b'[' => depth += 1,
+                b'}' | b')' | b']' => {
+                    depth = depth.checked_sub(1)?;
+                    if depth == 0 {
+                        return Some(i);
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        None
+    }
+}
+
+pub type Message = std::sync::Arc<str>;
+
+/// Where a message was emitted. Serialized as `file[:line[:column]]`, or as an object
+/// depending on the [`PositionsFormat`]. Both are accepted when reading.
+#[derive(Clone, Hash, PartialEq, Eq, Deserialize)]
+#[serde(from = "PositionRepr")]
+pub struct Position {
+    pub file: String,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+}
+
+impl Position {
+    fn from_file(file: String) -> Position {
+        Position {
+            file,
+            line: None,
+            column: None,
+        }
+    }
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.file)?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+            if let Some(column) = self.column {
+                write!(f, ":{}", column)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+impl From<String> for Position {
+    fn from(s: String) -> Position {
+        fn split_num(s: &str) -> Option<(&str, u32)> {
+            let (rest, num) = s.rsplit_once(':')?;
+            Some((rest, num.parse().ok()?))
+        }
+        match split_num(&s) {
+            Some((rest, last)) => match split_num(rest) {
+                Some((file, line)) => Position {
+                    file: file.to_string(),
+                    line: Some(line),
+                    column: Some(last),
+                },
+                None => Position {
+                    file: rest.to_string(),
+                    line: Some(last),
+                    column: None,
+                },
+            },
+            None => Position::from_file(s),
+        }
+    }
+}
+
+impl From<Position> for String {
+    fn from(pos: Position) -> String {
+        pos.to_string()
+    }
+}
+
+/// The serialized forms of a [`Position`]
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+enum PositionRepr {
+    String(String),
+    Object {
+        file: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        line: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        col: Option<u32>,
+    },
+}
+
+impl From<PositionRepr> for Position {
+    fn from(repr: PositionRepr) -> Position {
+        match repr {
+            PositionRepr::String(s) => s.into(),
+            PositionRepr::Object { file, line, col } => Position {
+                file,
+                line,
+                column: col,
+            },
+        }
+    }
+}
+
+impl Serialize for Position {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match POSITIONS_FORMAT.get() {
+            PositionsFormat::String => serializer.serialize_str(&self.to_string()),
+            PositionsFormat::JsonPointer => PositionRepr::Object {
+                file: self.file.clone(),
+                line: self.line,
+                col: self.column,
+            }
+            .serialize(serializer),
+        }
+    }
+}
+
+/// Kind of a `.nix` file, as flakes have a structure and evaluation of their own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FileClass {
+    /// A `flake.nix`
+    Flake,
+    /// Any other `.nix` file
+    Regular,
+}
+
+impl FileClass {
+    pub fn of(path: &Path) -> FileClass {
+        if path.file_name() == Some(OsStr::new("flake.nix")) {
+            FileClass::Flake
+        } else {
+            FileClass::Regular
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FileClass::Flake => "flake",
+            FileClass::Regular => "regular",
+        }
+    }
+}
+
+impl FromStr for FileClass {
+    type Err = ();
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "flake" | "flakes" => Ok(FileClass::Flake),
+            "regular" => Ok(FileClass::Regular),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How positions are written as JSON
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PositionsFormat {
+    /// `file[:line[:column]]`
+    #[default]
+    String,
+    /// `{ "file": …, "line": …, "col": … }`, for tools that want to jump to a position
+    JsonPointer,
+}
+
+impl FromStr for PositionsFormat {
+    type Err = ();
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "string" => Ok(PositionsFormat::String),
+            "json-pointer" => Ok(PositionsFormat::JsonPointer),
+            _ => Err(()),
+        }
+    }
+}
+
+thread_local! {
+    // Serde has no way to pass options down to `Position::serialize`
+    static POSITIONS_FORMAT: std::cell::Cell<PositionsFormat> = Default::default();
+}
+
+impl PositionsFormat {
+    /// Run `f`, with all positions it serializes written in this format
+    pub fn scope<R>(self, f: impl FnOnce() -> R) -> R {
+        let previous = POSITIONS_FORMAT.replace(self);
+        let result = f();
+        POSITIONS_FORMAT.set(previous);
+        result
+    }
+}
+
+/// Compare strings such that runs of digits are ordered by their numeric value,
+/// i.e. `file2.nix` comes before `file10.nix`
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (Some(ca), Some(cb)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len());
+        };
+        if ca.is_ascii_digit() && cb.is_ascii_digit() {
+            let len_a = a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len());
+            let len_b = b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len());
+            let (num_a, num_b) = (
+                a[..len_a].trim_start_matches('0'),
+                b[..len_b].trim_start_matches('0'),
+            );
+            let ord = num_a.len().cmp(&num_b.len()).then_with(|| num_a.cmp(num_b));
+            if ord.is_ne() {
+                return ord;
+            }
+            (a, b) = (&a[len_a..], &b[len_b..]);
+        } else {
+            if ca != cb {
+                return ca.cmp(&cb);
+            }
+            (a, b) = (&a[ca.len_utf8()..], &b[cb.len_utf8()..]);
+        }
+    }
+}
+
+impl Ord for Position {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        let mut components_a = Path::new(&self.file).components();
+        let mut components_b = Path::new(&other.file).components();
+        let files = loop {
+            match (components_a.next(), components_b.next()) {
+                (Some(a), Some(b)) => {
+                    let ord = natural_cmp(
+                        &a.as_os_str().to_string_lossy(),
+                        &b.as_os_str().to_string_lossy(),
+                    );
+                    if ord.is_ne() {
+                        break ord;
+                    }
+                }
+                (a, b) => break a.is_some().cmp(&b.is_some()),
+            }
+        };
+        files
+            .then(self.line.cmp(&other.line))
+            .then(self.column.cmp(&other.column))
+            // Keep consistent with `Eq` for paths like `a01` and `a1`
+            .then_with(|| self.file.cmp(&other.file))
+    }
+}
+
+impl PartialOrd for Position {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(feature = "schema")]
+impl schemars::JsonSchema for Position {
+    fn schema_name() -> String {
+        "Position".into()
+    }
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        PositionRepr::json_schema(gen)
+    }
+}
 
-#[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Default, Debug, Serialize, Deserialize)]
 struct Finds {
     positions: HashSet<Position>,
+    /// Number of positions left out of `positions` to bound memory use
+    #[serde(default)]
+    dropped: usize,
+    /// Original texts of the message, if they were simplified
+    #[serde(default)]
+    raw: HashSet<Message>,
+}
+
+/// Only the positions count, the wording of a simplified message may well differ
+impl PartialEq for Finds {
+    fn eq(&self, other: &Finds) -> bool {
+        self.positions == other.positions && self.dropped == other.dropped
+    }
+}
+
+impl Finds {
+    /// Add the positions of `other`, keeping at most `max_positions` of them as a sample
+    fn merge(&mut self, other: Finds, max_positions: Option<usize>) {
+        for raw in other.raw {
+            if max_positions.is_none_or(|max| self.raw.len() < max) {
+                self.raw.insert(raw);
+            }
+        }
+        self.dropped += other.dropped;
+        for pos in other.positions {
+            // Already in the sample, e.g. when merging overlapping results
+            if self.positions.contains(&pos) {
+                continue;
+            }
+            if max_positions.is_some_and(|max| self.positions.len() >= max) {
+                self.dropped += 1;
+            } else {
+                self.positions.insert(pos);
+            }
+        }
+    }
+}
+
+/// What the occurrences of messages are grouped by, see `--dedup-across-files`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct LogKey {
+    msg: Message,
+    /// Only set when messages are compared per file
+    file: Option<String>,
 }
 
-type CompLog = HashMap<Message, Finds>;
+type CompLog = HashMap<LogKey, Finds>;
 
 type ErrLog = CompLog;
 type WarnLog = CompLog;
 type TraceLog = CompLog;
+type ResultLog = CompLog;
 
 #[derive(Debug, Serialize, Deserialize, Default, Hash, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Diff<T> {
     pub result_a: T,
     pub result_b: T,
 }
 
+/// Names of the two sides of a [`Diff`], for display
+#[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Labels {
+    pub a: String,
+    pub b: String,
+}
+
+impl Default for Labels {
+    fn default() -> Labels {
+        Labels {
+            a: "a".into(),
+            b: "b".into(),
+        }
+    }
+}
+
+impl Labels {
+    /// Use the explicitly given labels, falling back to the given names as long as they tell
+    /// the two sides apart
+    fn new(
+        label_a: Option<String>,
+        label_b: Option<String>,
+        name_a: Option<String>,
+        name_b: Option<String>,
+    ) -> Labels {
+        let default = match (name_a, name_b) {
+            (Some(a), Some(b)) if a != b => Labels { a, b },
+            _ => Labels::default(),
+        };
+        Labels {
+            a: label_a.unwrap_or(default.a),
+            b: label_b.unwrap_or(default.b),
+        }
+    }
+
+    /// Pair both sides of a diff with their label
+    pub fn sides<T>(&self, diff: Diff<T>) -> [(&str, T); 2] {
+        [(&self.a, diff.result_a), (&self.b, diff.result_b)]
+    }
+}
+
+/// The last component of a path, as a label
+fn base_name(path: &Path) -> Option<String> {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+/// How many differing stdouts to keep when merging the diffs of many files
+const MAX_STDOUT_SAMPLES: usize = 10;
+
+/// Differing stdouts, possibly merged over many files
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct StdoutDiff {
+    /// Number of files whose stdout differed
+    count: usize,
+    /// Up to [`MAX_STDOUT_SAMPLES`] of those stdouts
+    samples: Vec<Diff<Message>>,
+}
+
+impl StdoutDiff {
+    fn merge(&mut self, other: StdoutDiff) {
+        self.count += other.count;
+        let room = MAX_STDOUT_SAMPLES.saturating_sub(self.samples.len());
+        self.samples.extend(other.samples.into_iter().take(room));
+    }
+}
+
+/// A node of the parsed AST that differs between the parsers, from `--ast-diff`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct AstChange {
+    /// Where the node is, starting from the root `$`, through `.<name>` for the bindings of
+    /// attribute sets and `[<index>]` for the tokens of anything else
+    pub path: String,
+    /// The node on both sides, shortened. Missing on the side that doesn't have it
+    pub node: Diff<Option<Message>>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 struct ParserDiff {
     // if both sides passed, otherwise info which didn't pass
     pass_eq: Option<Diff<bool>>,
     // exit code difference
     exit_eq: Option<Diff<Option<i32>>>,
-    stdout_eq: Option<Diff<Message>>,
+    stdout_eq: Option<StdoutDiff>,
+    // differing nodes of the stdout ASTs, with `--ast-diff`
+    #[serde(default)]
+    ast_eq: Vec<AstChange>,
     err_eq: Option<Diff<ErrLog>>,
     warn_eq: Option<Diff<WarnLog>>,
     trace_eq: Option<Diff<TraceLog>>,
+    result_eq: Option<Diff<ResultLog>>,
+    // messages that only differ in wording, compared as one
+    #[serde(default)]
+    reworded: HashSet<Diff<Message>>,
+    // wall time of both parsers, if one was much slower
+    #[serde(default)]
+    perf_eq: Option<Diff<Duration>>,
+    // which parsers emitted each message at all, not only at the differing positions
+    #[serde(default)]
+    emitted: HashMap<Message, Diff<bool>>,
 }
 
 impl Diff<CompLog> {
@@ -134,17 +992,17 @@ impl Diff<CompLog> {
                 if b.contains_key(key) {
                     let not_in_b = a[key].positions.difference(&b[key].positions);
                     for pos in not_in_b {
-                        res_a
-                            .entry(key.clone())
-                            .or_insert(Default::default())
-                            .positions
-                            .insert(pos.clone());
+                        let finds = res_a.entry(key.clone()).or_insert(Default::default());
+                        finds.positions.insert(pos.clone());
+                        finds.raw.clone_from(&a[key].raw);
                     }
                 } else {
                     res_a.insert(
-                        key.into(),
+                        key.clone(),
                         Finds {
                             positions: a[key].positions.clone(),
+                            dropped: 0,
+                            raw: a[key].raw.clone(),
                         },
                     );
                 }
@@ -161,15 +1019,21 @@ impl Diff<CompLog> {
         }
     }
 
-    pub fn merge(&mut self, b: Diff<CompLog>) {
-        self.result_a.extend(b.result_a);
-        self.result_b.extend(b.result_b);
+    pub fn merge(&mut self, b: Diff<CompLog>, max_positions: Option<usize>) {
+        for (log, other) in [
+            (&mut self.result_a, b.result_a),
+            (&mut self.result_b, b.result_b),
+        ] {
+            for (msg, finds) in other {
+                log.entry(msg).or_default().merge(finds, max_positions);
+            }
+        }
     }
 }
 macro_rules! merge_complog {
-    ($a: expr, $b: expr) => {
+    ($a: expr, $b: expr $(, $arg: expr)*) => {
         match ($a.as_mut(), $b) {
-            (Some(a), Some(b)) => a.merge(b),
+            (Some(a), Some(b)) => a.merge(b $(, $arg)*),
             (None, b) => $a = b,
             _ => (),
         }
@@ -177,7 +1041,19 @@ macro_rules! merge_complog {
 }
 
 impl ParserDiff {
-    fn merge(&mut self, other: ParserDiff) {
+    fn is_empty(&self) -> bool {
+        self.pass_eq.is_none()
+            && self.exit_eq.is_none()
+            && self.stdout_eq.is_none()
+            && self.err_eq.is_none()
+            && self.warn_eq.is_none()
+            && self.trace_eq.is_none()
+            && self.result_eq.is_none()
+            && self.reworded.is_empty()
+            && self.perf_eq.is_none()
+    }
+
+    fn merge(&mut self, other: ParserDiff, max_positions: Option<usize>) {
         match (self.pass_eq.is_none(), other.pass_eq) {
             (true, Some(s)) => {
                 self.pass_eq.replace(s);
@@ -191,169 +1067,1093 @@ impl ParserDiff {
             _ => (),
         }
 
-        self.stdout_eq = match (self.stdout_eq.take(), other.stdout_eq) {
-            (Some(_), Some(_)) => Some(Diff {
-                result_a: "Multiple given".to_string(),
-                result_b: "Multiple given".to_string(),
-            }),
-            (None, b) => b,
-            (a, None) => a,
+        merge_complog!(self.stdout_eq, other.stdout_eq);
+        merge_complog!(self.err_eq, other.err_eq, max_positions);
+        merge_complog!(self.warn_eq, other.warn_eq, max_positions);
+        merge_complog!(self.trace_eq, other.trace_eq, max_positions);
+        merge_complog!(self.result_eq, other.result_eq, max_positions);
+        self.reworded.extend(other.reworded);
+        for (msg, emitted) in other.emitted {
+            let entry = self.emitted.entry(msg).or_default();
+            entry.result_a |= emitted.result_a;
+            entry.result_b |= emitted.result_b;
+        }
+    }
+}
+
+/// The messages that only one side emitted, of those in `remaining`
+fn one_sided_messages(
+    emitted: HashMap<Message, Diff<bool>>,
+    remaining: &HashSet<&Message>,
+) -> Diff<HashSet<Message>> {
+    let mut one_sided = Diff::<HashSet<Message>>::default();
+    for (msg, emitted) in emitted {
+        if !remaining.contains(&msg) {
+            continue;
+        }
+        match (emitted.result_a, emitted.result_b) {
+            (true, false) => one_sided.result_a.insert(msg),
+            (false, true) => one_sided.result_b.insert(msg),
+            _ => false,
         };
+    }
+    one_sided
+}
 
-        merge_complog!(self.err_eq, other.err_eq);
-        merge_complog!(self.warn_eq, other.warn_eq);
-        merge_complog!(self.trace_eq, other.trace_eq);
+/// Remove the first of `prefixes` that `path` starts with
+fn strip_prefixes<'a>(path: &'a Path, prefixes: &[PathBuf]) -> &'a Path {
+    prefixes
+        .iter()
+        .find_map(|prefix| path.strip_prefix(prefix).ok())
+        .unwrap_or(path)
+}
+
+/// Rename the messages of `log_b` that are missing in `log_a`, but only differ in wording from a
+/// message there that is missing in `log_b`, so that both get compared as one.
+/// Returns the renamed pairs.
+fn pair_reworded(log_a: &CompLog, log_b: &mut CompLog, threshold: f64) -> HashSet<Diff<Message>> {
+    let mut only_a: Vec<&LogKey> = log_a
+        .keys()
+        .filter(|key| !log_b.contains_key(*key))
+        .collect();
+    let mut only_b: Vec<LogKey> = log_b
+        .keys()
+        .filter(|key| !log_a.contains_key(*key))
+        .cloned()
+        .collect();
+    // Deterministic pairing when several messages are similar
+    only_a.sort();
+    only_b.sort();
+
+    let mut pairs = HashSet::new();
+    for key_b in only_b {
+        let best = only_a
+            .iter()
+            .enumerate()
+            // Messages in different files are never the same one
+            .filter(|(_, key_a)| key_a.file == key_b.file)
+            .map(|(i, key_a)| (i, strsim::normalized_levenshtein(&key_a.msg, &key_b.msg)))
+            .filter(|(_, similarity)| *similarity >= threshold)
+            .max_by(|x, y| x.1.total_cmp(&y.1));
+        if let Some((i, _)) = best {
+            let key_a = only_a.remove(i);
+            let finds = log_b.remove(&key_b).unwrap();
+            log_b.insert(key_a.clone(), finds);
+            pairs.insert(Diff {
+                result_a: key_a.msg.clone(),
+                result_b: key_b.msg,
+            });
+        }
     }
+    pairs
+}
+
+/// How the stderr of both parsers differs
+#[derive(Default)]
+struct StderrDiff {
+    err: Option<Diff<ErrLog>>,
+    warn: Option<Diff<WarnLog>>,
+    trace: Option<Diff<TraceLog>>,
+    result: Option<Diff<ResultLog>>,
+    // messages that only differ in wording, compared as one
+    reworded: HashSet<Diff<Message>>,
+    // which parsers emitted each message at all
+    emitted: HashMap<Message, Diff<bool>>,
 }
 
 fn diff_stderr(
     err_a: String,
     err_b: String,
     file: &Path,
-) -> (
-    Option<Diff<ErrLog>>,
-    Option<Diff<WarnLog>>,
-    Option<Diff<TraceLog>>,
-) {
+    options: &DiffOptions,
+    stats: &LineStats,
+) -> StderrDiff {
     if err_a != err_b {
-        let (err_a, wrn_a, trc_a) = parsing::split_stderr(err_a, file);
-        let (err_b, wrn_b, trc_b) = parsing::split_stderr(err_b, file);
-        (
-            (err_a != err_b).then_some(Diff::from(err_a, err_b)),
-            (wrn_a != wrn_b).then_some(Diff::from(wrn_a, wrn_b)),
-            (trc_a != trc_b).then_some(Diff::from(trc_a, trc_b)),
-        )
-    } else {
-        (None, None, None)
-    }
-}
-
-#[tracing::instrument(skip(nix_a, nix_b))]
-async fn diff_file(
-    file: &Path,
-    nix_a: &Path,
-    nix_b: &Path,
-) -> color_eyre::Result<Option<ParserDiff>> {
-    /* Execute the parsers */
-    let run = |nix: &Path, runner: &str| {
-        tokio::process::Command::new(nix)
-            .arg0("nix-instantiate")
-            .arg("--parse")
-            .arg("--log-format")
-            .arg("internal-json")
-            .arg(file)
+        let (err_a, wrn_a, trc_a, res_a) =
+            parsing::split_stderr(err_a, Side::A, file, options, stats);
+        let (mut err_b, mut wrn_b, mut trc_b, mut res_b) =
+            parsing::split_stderr(err_b, Side::B, file, options, stats);
+        let mut reworded = HashSet::new();
+        if let Some(threshold) = options.reword_threshold {
+            for (dimension, log_a, log_b) in [
+                (Dimension::Err, &err_a, &mut err_b),
+                (Dimension::Warn, &wrn_a, &mut wrn_b),
+                (Dimension::Trace, &trc_a, &mut trc_b),
+                (Dimension::Result, &res_a, &mut res_b),
+            ] {
+                // Rewordings count as differences, so only of the messages that are compared
+                if options.is_selected(dimension) {
+                    reworded.extend(pair_reworded(log_a, log_b, threshold));
+                }
+            }
+        }
+        let mut emitted: HashMap<Message, Diff<bool>> = HashMap::new();
+        for log in [&err_a, &wrn_a, &trc_a, &res_a] {
+            for key in log.keys() {
+                emitted.entry(key.msg.clone()).or_default().result_a = true;
+            }
+        }
+        for log in [&err_b, &wrn_b, &trc_b, &res_b] {
+            for key in log.keys() {
+                emitted.entry(key.msg.clone()).or_default().result_b = true;
+            }
+        }
+        StderrDiff {
+            err: (err_a != err_b).then_some(Diff::from(err_a, err_b)),
+            warn: (wrn_a != wrn_b).then_some(Diff::from(wrn_a, wrn_b)),
+            trace: (trc_a != trc_b).then_some(Diff::from(trc_a, trc_b)),
+            result: (res_a != res_b).then_some(Diff::from(res_a, res_b)),
+            reworded,
+            emitted,
+        }
+    } else {
+        StderrDiff::default()
+    }
+}
+
+/// How to invoke one of the two Nix versions under comparison
+#[derive(Debug, Clone)]
+pub enum NixCommand {
+    /// A Nix binary on this machine
+    Local(PathBuf),
+    /// A Nix binary on another machine, reached via `ssh <host>`.
+    /// The scanned files must be available under the same path there.
+    Ssh { host: String, nix: PathBuf },
+    /// Outputs recorded earlier with [`record_corpus`] instead of a live binary
+    Recorded(Arc<Corpus>),
+    /// A whitespace separated command line, e.g. `nix run nixpkgs#nix -- nix-instantiate`.
+    /// The arguments replace a `{}` word, or are appended if there is none
+    Template(Vec<String>),
+}
+
+/// Word of a [`NixCommand::Template`] that is replaced by the arguments
+const TEMPLATE_ARGS: &str = "{}";
+
+impl FromStr for NixCommand {
+    type Err = ();
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        if let Some(template) = s.strip_prefix("cmd:") {
+            let words: Vec<String> = template.split_whitespace().map(String::from).collect();
+            return match words.first() {
+                Some(_) => Ok(NixCommand::Template(words)),
+                None => Err(()),
+            };
+        }
+        match s.strip_prefix("ssh://") {
+            Some(remote) => match remote.split_once(':') {
+                Some((host, nix)) if !host.is_empty() && !nix.is_empty() => Ok(NixCommand::Ssh {
+                    host: host.to_string(),
+                    nix: nix.into(),
+                }),
+                _ => Err(()),
+            },
+            None => Ok(NixCommand::Local(s.into())),
+        }
+    }
+}
+
+impl NixCommand {
+    /// Prepare running the binary with `args`, named `arg0` if it is local.
+    /// `None` selects [`PARSE_ARG0`], an empty string keeps the binary's own name
+    pub fn command(&self, arg0: Option<&str>, args: &[&OsStr]) -> tokio::process::Command {
+        match self {
+            NixCommand::Local(nix) => {
+                let mut command = tokio::process::Command::new(nix);
+                match arg0 {
+                    None => {
+                        command.arg0(PARSE_ARG0);
+                    }
+                    Some("") => {}
+                    Some(arg0) => {
+                        command.arg0(arg0);
+                    }
+                }
+                command.args(args);
+                command
+            }
+            // Remote binaries can't be tricked via arg0, so they need to point at `nix-instantiate` directly
+            NixCommand::Ssh { host, nix } => {
+                let mut command = tokio::process::Command::new("ssh");
+                command.arg(host).arg("--").arg(
+                    std::iter::once(nix.as_os_str())
+                        .chain(args.iter().copied())
+                        .map(shell_quote)
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                );
+                command
+            }
+            // The command decides what to run, so arg0 doesn't apply either
+            NixCommand::Template(words) => {
+                let mut command = tokio::process::Command::new(&words[0]);
+                for word in &words[1..] {
+                    if word == TEMPLATE_ARGS {
+                        command.args(args);
+                    } else {
+                        command.arg(word);
+                    }
+                }
+                if !words.iter().any(|word| word == TEMPLATE_ARGS) {
+                    command.args(args);
+                }
+                command
+            }
+            NixCommand::Recorded(_) => unreachable!("Recorded outputs are looked up, not run"),
+        }
+    }
+
+    /// Output of `--version`, or `None` if that can't be run
+    async fn version(&self) -> Option<String> {
+        if let NixCommand::Recorded(_) = self {
+            return None;
+        }
+        let output = self
+            .command(Some(""), &[OsStr::new("--version")])
             .stdin(Stdio::null())
-            // Cancellation safety
             .kill_on_drop(true)
             .output()
-            .instrument(tracing::info_span!("Executing `nix-instantiate --parse`", runner, file = %file.display()))
+            .await
+            .ok()
+            .filter(|output| output.status.success())?;
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Whether both commands run the very same binary, e.g. via two symlinks to it
+    fn is_same_binary(&self, other: &NixCommand) -> bool {
+        let canonical = |nix: &PathBuf| nix.canonicalize().unwrap_or_else(|_| nix.clone());
+        match (self, other) {
+            (NixCommand::Local(a), NixCommand::Local(b)) => canonical(a) == canonical(b),
+            (NixCommand::Ssh { host: ha, nix: a }, NixCommand::Ssh { host: hb, nix: b }) => {
+                ha == hb && a == b
+            }
+            (NixCommand::Template(a), NixCommand::Template(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Name of the binary, without the directory it is in
+    fn base_name(&self) -> Option<String> {
+        match self {
+            NixCommand::Local(nix) | NixCommand::Ssh { nix, .. } => base_name(nix),
+            NixCommand::Recorded(corpus) => base_name(&corpus.nix),
+            // Commands tend to end in the same `nix-instantiate`, so that's no way to tell them apart
+            NixCommand::Template(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for NixCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NixCommand::Local(nix) => write!(f, "{}", nix.display()),
+            NixCommand::Ssh { host, nix } => write!(f, "ssh://{}:{}", host, nix.display()),
+            NixCommand::Recorded(corpus) => write!(f, "{} (recorded)", corpus.nix.display()),
+            NixCommand::Template(words) => write!(f, "cmd:{}", words.join(" ")),
+        }
+    }
+}
+
+/// Quote an argument for the remote shell `ssh` hands its command line to
+fn shell_quote(arg: &OsStr) -> String {
+    format!("'{}'", arg.to_string_lossy().replace('\'', "'\\''"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Side {
+    A,
+    B,
+}
+
+/// What happened when diffing a single file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum FileOutcome {
+    /// The parser did not finish within `--timeout`
+    Timeout { side: Side },
+    /// The parser could not be run at all
+    SpawnError { side: Side, error: String },
+    /// The parser produced output that is not valid UTF-8
+    NonUtf8 { side: Side },
+    /// The file is missing from the recorded corpus
+    NotRecorded { side: Side },
+    /// Both parsers ran, and their results differ
+    Diff,
+}
+
+/// Share of unparseable `@nix` lines above which `--fail-on-parse-error` aborts
+const MAX_PARSE_ERROR_RATE: f64 = 0.1;
+/// Lines to see before aborting early, so that a single odd file doesn't end the run
+const MIN_PARSE_ERROR_SAMPLE: usize = 100;
+
+/// How many of the `@nix` lines of each side could be parsed, to notice a mismatched log format
+#[derive(Debug, Default)]
+struct LineStats {
+    lines: [std::sync::atomic::AtomicUsize; 2],
+    failed: [std::sync::atomic::AtomicUsize; 2],
+}
+
+impl LineStats {
+    fn record(&self, side: Side, ok: bool) {
+        use std::sync::atomic::Ordering;
+
+        let side = side as usize;
+        self.lines[side].fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.failed[side].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Fail if too many lines of one side could not be parsed, once at least `min_lines` were seen
+    fn check(&self, nix: [&NixCommand; 2], min_lines: usize) -> color_eyre::Result<()> {
+        use std::sync::atomic::Ordering;
+
+        for (side, nix) in nix.into_iter().enumerate() {
+            let lines = self.lines[side].load(Ordering::Relaxed);
+            let failed = self.failed[side].load(Ordering::Relaxed);
+            if lines > 0
+                && lines >= min_lines
+                && failed as f64 / lines as f64 > MAX_PARSE_ERROR_RATE
+            {
+                color_eyre::eyre::bail!(
+                    "{} of {} `@nix` lines from {} could not be parsed. \
+                    It probably does not support `--log-format internal-json`, \
+                    or some `--nix-arg` changes its output",
+                    failed,
+                    lines,
+                    nix
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reusable buffers for capturing process output, so that the common case of files without
+/// any differences doesn't allocate fresh buffers every time
+struct BufferPool {
+    buffers: std::sync::Mutex<Vec<Vec<u8>>>,
+    /// Initial capacity of new buffers, and what returned ones are shrunk back to
+    size: usize,
+}
+
+impl BufferPool {
+    /// Enough for both outputs of both sides of all concurrently diffed files
+    const MAX_POOLED: usize = 4 * MAX_CONCURRENT_FILES;
+
+    fn new(size: usize) -> BufferPool {
+        BufferPool {
+            buffers: Default::default(),
+            size,
+        }
+    }
+
+    fn take(&self) -> Vec<u8> {
+        self.buffers
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(self.size))
+    }
+
+    fn give_back(&self, mut buffer: Vec<u8>) {
+        buffer.clear();
+        buffer.shrink_to(self.size);
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < Self::MAX_POOLED {
+            buffers.push(buffer);
+        }
+    }
+}
+
+/// Run a command to completion, capturing its output into buffers from the pool
+async fn capture_output(
+    mut command: tokio::process::Command,
+    buffers: &BufferPool,
+) -> std::io::Result<Output> {
+    use tokio::io::AsyncReadExt;
+
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let (mut stdout, mut stderr) = (buffers.take(), buffers.take());
+    let mut child_stdout = child.stdout.take().expect("stdout is piped");
+    let mut child_stderr = child.stderr.take().expect("stderr is piped");
+    tokio::try_join!(
+        child_stdout.read_to_end(&mut stdout),
+        child_stderr.read_to_end(&mut stderr),
+    )?;
+    let status = child.wait().await?;
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+/// Name under which a multi-call `nix` binary behaves like `nix-instantiate`
+const PARSE_ARG0: &str = "nix-instantiate";
+
+/// The whole environment of parsers run with `--clean-env`, apart from `PATH`.
+/// The home directory is the one Nix' own build sandbox uses, which doesn't exist
+const CLEAN_ENV: &[(&str, &str)] = &[
+    ("HOME", "/homeless-shelter"),
+    ("NIX_PATH", ""),
+    ("LC_ALL", "C.UTF-8"),
+    ("TZ", "UTC"),
+];
+
+/// Run `nix` on `file`, or look up its recorded output relative to `root`
+async fn run_parser(
+    nix: &NixCommand,
+    side: Side,
+    file: &Path,
+    root: &Path,
+    options: &DiffOptions,
+    buffers: &BufferPool,
+) -> Result<Output, FileOutcome> {
+    if let NixCommand::Recorded(corpus) = nix {
+        return corpus
+            .output(file.strip_prefix(root).unwrap_or(file))
+            .ok_or(FileOutcome::NotRecorded { side });
+    }
+    let args: Vec<&OsStr> = [
+        "--parse".as_ref(),
+        "--log-format".as_ref(),
+        "internal-json".as_ref(),
+        file.as_os_str(),
+    ]
+    .into_iter()
+    .chain(options.nix_arg.iter().map(OsStr::new))
+    .collect();
+    let mut command = nix.command(options.arg0.as_deref(), &args);
+    if options.clean_env && !matches!(nix, NixCommand::Ssh { .. }) {
+        command.env_clear().envs(CLEAN_ENV.iter().copied());
+        // Keeps finding the programs of command templates working
+        if let Some(path) = std::env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+    }
+    command
+        .stdin(Stdio::null())
+        // Cancellation safety, this also kills the process on timeout
+        .kill_on_drop(true);
+    let output = capture_output(command, buffers);
+    let runner = match side {
+        Side::A => "nix_a",
+        Side::B => "nix_b",
     };
-    let result_a = run(nix_a, "nix_a");
-    let result_b = run(nix_b, "nix_b");
-    let (result_a, result_b) = futures::join!(result_a, result_b);
+    async move {
+        let output = match options.timeout {
+            Some(secs) => tokio::time::timeout(Duration::from_secs(secs), output)
+                .await
+                .map_err(|_| FileOutcome::Timeout { side })?,
+            None => output.await,
+        };
+        output.map_err(|err| FileOutcome::SpawnError {
+            side,
+            error: err.to_string(),
+        })
+    }
+    .instrument(
+        tracing::info_span!("Executing `nix-instantiate --parse`", runner, file = %file.display()),
+    )
+    .await
+}
+
+#[tracing::instrument(skip(root, nix_a, nix_b, options, buffers, stats))]
+async fn diff_file(
+    file: &Path,
+    root: &Path,
+    nix_a: &NixCommand,
+    nix_b: &NixCommand,
+    options: &DiffOptions,
+    buffers: &BufferPool,
+    stats: &LineStats,
+) -> Result<Option<ParserDiff>, FileOutcome> {
+    /* Execute the parsers */
+    let timed = |side, nix| async move {
+        let start = std::time::Instant::now();
+        let output = run_parser(nix, side, file, root, options, buffers).await;
+        (output, start.elapsed())
+    };
+    let ((result_a, time_a), (result_b, time_b)) =
+        futures::join!(timed(Side::A, nix_a), timed(Side::B, nix_b));
     let (result_a, result_b) = (result_a?, result_b?);
+    let perf_eq = options
+        .perf_ratio
+        .filter(|&ratio| is_much_slower(time_a, time_b, ratio))
+        .map(|_| Diff {
+            result_a: time_a,
+            result_b: time_b,
+        });
 
     /* compare Results */
     //dbg!(&result_a, &result_b);
-    let res = if result_a != result_b {
-        let pass = result_a.status.success() && result_b.status.success();
-        let exit = result_a.status == result_b.status;
-        let stdout = result_a.stdout == result_b.stdout;
-        let (err, warn, trace) = diff_stderr(
-            String::from_utf8(result_a.stderr)?,
-            String::from_utf8(result_b.stderr)?,
-            file,
-        );
+    if result_a == result_b {
+        for output in [result_a, result_b] {
+            buffers.give_back(output.stdout);
+            buffers.give_back(output.stderr);
+        }
+        return Ok(perf_eq.map(|perf_eq| ParserDiff {
+            perf_eq: Some(perf_eq),
+            ..Default::default()
+        }));
+    }
+    if let Some(dir) = &options.save_raw {
+        let prefix = dir.join(file.strip_prefix(root).unwrap_or(file));
+        for (side, output) in [("a", &result_a), ("b", &result_b)] {
+            let mut path = prefix.clone().into_os_string();
+            path.push(".");
+            path.push(side);
+            if let Err(err) = save_captured_output(Path::new(&path), output) {
+                tracing::warn!(file = %file.display(), ?err, "Failed to save the raw output");
+            }
+        }
+    }
+    let diff = compare_outputs(file, result_a, result_b, options, stats)?;
+    Ok(match (diff, perf_eq) {
+        (Some(diff), perf_eq) => Some(ParserDiff { perf_eq, ..diff }),
+        (None, perf_eq) => perf_eq.map(|perf_eq| ParserDiff {
+            perf_eq: Some(perf_eq),
+            ..Default::default()
+        }),
+    })
+}
 
-        Some(ParserDiff {
-            pass_eq: (!pass).then_some(Diff {
-                result_a: result_a.status.success(),
-                result_b: result_b.status.success(),
-            }),
-            exit_eq: (!exit).then_some(Diff {
-                result_a: result_a.status.code(),
-                result_b: result_b.status.code(),
-            }),
-            stdout_eq: (!stdout).then_some(Diff {
-                result_a: String::from_utf8(result_a.stdout)?,
-                result_b: String::from_utf8(result_b.stdout)?,
-            }),
-            err_eq: err,
-            warn_eq: warn,
-            trace_eq: trace,
-        })
+/// Parses faster than this are dominated by process startup, and too noisy to compare
+const MIN_PERF_DURATION: Duration = Duration::from_millis(100);
+
+/// Whether one of the parsers took more than `ratio` times as long as the other
+fn is_much_slower(time_a: Duration, time_b: Duration, ratio: f64) -> bool {
+    let (fast, slow) = (time_a.min(time_b), time_a.max(time_b));
+    slow >= MIN_PERF_DURATION && slow.as_secs_f64() > fast.as_secs_f64() * ratio
+}
+
+/// Compare the outputs of both parsers on `file`
+fn compare_outputs(
+    file: &Path,
+    result_a: Output,
+    result_b: Output,
+    options: &DiffOptions,
+    stats: &LineStats,
+) -> Result<Option<ParserDiff>, FileOutcome> {
+    if result_a == result_b {
+        return Ok(None);
+    }
+    let utf8 = |bytes: Vec<u8>, side: Side| {
+        String::from_utf8(bytes).map_err(|_| FileOutcome::NonUtf8 { side })
+    };
+    let pass = !options.is_selected(Dimension::Pass)
+        || (result_a.status.success() && result_b.status.success());
+    let exit = !options.is_selected(Dimension::Exit) || result_a.status == result_b.status;
+    let stdout = !options.is_selected(Dimension::Stdout)
+        || result_a.stdout == result_b.stdout
+        || (options.canonicalize_ast
+            && canonical::canonicalize_ast(&String::from_utf8_lossy(&result_a.stdout))
+                == canonical::canonicalize_ast(&String::from_utf8_lossy(&result_b.stdout)));
+    // Without a successful parse on both sides, there are no two ASTs to compare
+    let ast_eq =
+        if !stdout && options.ast_diff && result_a.status.success() && result_b.status.success() {
+            ast::diff(
+                &String::from_utf8_lossy(&result_a.stdout),
+                &String::from_utf8_lossy(&result_b.stdout),
+            )
+        } else {
+            Vec::new()
+        };
+    // Parsing the messages is the expensive part, so skip it when none of them are of interest
+    let stderr = if [
+        Dimension::Err,
+        Dimension::Warn,
+        Dimension::Trace,
+        Dimension::Result,
+    ]
+    .into_iter()
+    .any(|dimension| options.is_selected(dimension))
+    {
+        diff_stderr(
+            utf8(result_a.stderr, Side::A)?,
+            utf8(result_b.stderr, Side::B)?,
+            file,
+            options,
+            stats,
+        )
     } else {
-        None
+        StderrDiff::default()
+    };
+
+    let diff = ParserDiff {
+        pass_eq: (!pass).then_some(Diff {
+            result_a: result_a.status.success(),
+            result_b: result_b.status.success(),
+        }),
+        exit_eq: (!exit).then_some(Diff {
+            result_a: result_a.status.code(),
+            result_b: result_b.status.code(),
+        }),
+        stdout_eq: (!stdout).then_some(StdoutDiff {
+            count: 1,
+            samples: vec![Diff {
+                result_a: utf8(result_a.stdout, Side::A)?.into(),
+                result_b: utf8(result_b.stdout, Side::B)?.into(),
+            }],
+        }),
+        ast_eq,
+        err_eq: stderr.err.filter(|_| options.is_selected(Dimension::Err)),
+        warn_eq: stderr.warn.filter(|_| options.is_selected(Dimension::Warn)),
+        trace_eq: stderr
+            .trace
+            .filter(|_| options.is_selected(Dimension::Trace)),
+        result_eq: stderr
+            .result
+            .filter(|_| options.is_selected(Dimension::Result)),
+        reworded: stderr.reworded,
+        perf_eq: None,
+        emitted: stderr.emitted,
     };
-    Ok(res)
+    // Outputs may only differ in ways that don't count
+    Ok((!diff.is_empty()).then_some(diff))
 }
 
 pub type MessageOccurrences = HashMap<Message, Diff<HashSet<Position>>>;
 
 #[derive(Default, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct DiffResult {
     pub stdout_diff: HashSet<Diff<Message>>,
+    /// Number of files whose stdout differed, including those where a side failed
+    #[serde(default)]
+    pub stdout_diff_count: usize,
+    /// Differing nodes of the stdout ASTs, with `--ast-diff`, ordered by path
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ast_diff: Vec<(PathBuf, Vec<AstChange>)>,
     pub err_diff: MessageOccurrences,
     pub wrn_diff: MessageOccurrences,
     pub trc_diff: MessageOccurrences,
+    /// Results of evaluation, as opposed to log messages
+    #[serde(default)]
+    pub res_diff: MessageOccurrences,
+    /// Files that differed or could not be diffed, ordered by path
+    #[serde(default)]
+    pub file_outcomes: Vec<(PathBuf, FileOutcome)>,
+    /// Names of the two compared parsers
+    #[serde(default)]
+    pub labels: Labels,
+    /// Messages that were compared as one, as they only differ in wording
+    #[serde(default)]
+    pub reworded: HashSet<Diff<Message>>,
+    /// Messages with more positions than `--max-positions`, whose positions above are only a
+    /// sample, with their total number of positions
+    #[serde(default)]
+    pub truncated: HashMap<Message, Diff<usize>>,
+    /// Original texts of simplified messages, with `--keep-raw-messages`
+    #[serde(default)]
+    pub raw_messages: HashMap<Message, HashSet<Message>>,
+    /// Number of files that were diffed, to tell an empty folder apart from one without
+    /// differences. Unknown for older results
+    #[serde(default)]
+    pub files_scanned: Option<usize>,
+    /// Wall time of both parsers for the files where one was slower by more than `--perf-ratio`,
+    /// ordered by path
+    #[serde(default)]
+    pub perf_diff: Vec<(PathBuf, Diff<Duration>)>,
+    /// Messages that only one of the parsers emitted at all, in the files where they differed.
+    /// New and removed diagnostics, as opposed to ones that moved
+    #[serde(default)]
+    pub one_sided: Diff<HashSet<Message>>,
+    /// The invocation that produced the result. Results renormalized later keep it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<crate::provenance::Provenance>,
 }
 
 impl DiffResult {
-    fn from(diffs: Vec<ParserDiff>) -> DiffResult {
-        if diffs.len() == 0 {
-            return Default::default();
+    /// Which parsers emitted `msg`, one of the messages of the result
+    pub fn emitted_by(&self, msg: &Message) -> Diff<bool> {
+        Diff {
+            result_a: !self.one_sided.result_b.contains(msg),
+            result_b: !self.one_sided.result_a.contains(msg),
         }
+    }
 
-        let mut out_diffs = HashSet::new();
+    /// All messages the result has positions of, in any category
+    pub fn messages(&self) -> HashSet<&Message> {
+        [
+            &self.err_diff,
+            &self.wrn_diff,
+            &self.trc_diff,
+            &self.res_diff,
+        ]
+        .into_iter()
+        .flat_map(HashMap::keys)
+        .collect()
+    }
+}
 
-        for diff in &diffs {
-            if diff.pass_eq.is_none() && diff.stdout_eq.is_some() {
-                out_diffs.insert(diff.stdout_eq.clone().unwrap());
-            }
+/// Merges the diffs of many files into a [`DiffResult`], one at a time
+struct DiffMerger {
+    stdout_diff: HashSet<Diff<Message>>,
+    merged: Option<ParserDiff>,
+    max_positions: Option<usize>,
+}
+
+impl DiffMerger {
+    fn new(max_positions: Option<usize>) -> DiffMerger {
+        DiffMerger {
+            stdout_diff: HashSet::new(),
+            merged: None,
+            max_positions,
         }
+    }
 
-        let rep = diffs
-            .into_iter()
-            .reduce(|mut acc, diff| {
-                acc.merge(diff);
-                acc
-            })
-            .unwrap();
+    fn add(&mut self, diff: ParserDiff) {
+        if let (None, Some(stdout)) = (&diff.pass_eq, &diff.stdout_eq) {
+            self.stdout_diff.extend(stdout.samples.iter().cloned());
+        }
+        self.merged
+            .get_or_insert_with(Default::default)
+            .merge(diff, self.max_positions);
+    }
 
-        fn propagate_msg(log: Option<Diff<CompLog>>) -> HashMap<Message, Diff<HashSet<Position>>> {
-            let mut hm: HashMap<Message, Diff<HashSet<Position>>> = HashMap::default();
-            if log.is_none() {
+    fn finish(self) -> DiffResult {
+        let Some(rep) = self.merged else {
+            return Default::default();
+        };
+        let mut truncated = HashMap::new();
+        let mut raw_messages: HashMap<Message, HashSet<Message>> = HashMap::new();
+
+        let mut propagate_msg = |log: Option<Diff<CompLog>>| -> MessageOccurrences {
+            let mut hm: MessageOccurrences = HashMap::default();
+            let Some(log) = log else {
                 return hm;
+            };
+            let mut counts: HashMap<Message, Diff<usize>> = HashMap::new();
+            // Messages compared per file are reported together again, their positions tell
+            // the files apart
+            for (LogKey { msg, .. }, poss) in log.result_a {
+                counts.entry(msg.clone()).or_default().result_a +=
+                    poss.positions.len() + poss.dropped;
+                if !poss.raw.is_empty() {
+                    raw_messages
+                        .entry(msg.clone())
+                        .or_default()
+                        .extend(poss.raw);
+                }
+                hm.entry(msg)
+                    .or_insert(Default::default())
+                    .result_a
+                    .extend(poss.positions);
             }
-            let log = log.unwrap();
-            for (msg, poss) in log.result_a {
-                hm.entry(msg).or_insert(Default::default()).result_a = poss.positions;
-            }
-            for (msg, poss) in log.result_b {
-                hm.entry(msg).or_insert(Default::default()).result_b = poss.positions;
+            for (LogKey { msg, .. }, poss) in log.result_b {
+                counts.entry(msg.clone()).or_default().result_b +=
+                    poss.positions.len() + poss.dropped;
+                if !poss.raw.is_empty() {
+                    raw_messages
+                        .entry(msg.clone())
+                        .or_default()
+                        .extend(poss.raw);
+                }
+                hm.entry(msg)
+                    .or_insert(Default::default())
+                    .result_b
+                    .extend(poss.positions);
             }
+            truncated.extend(counts.into_iter().filter(|(msg, count)| {
+                count.result_a > hm[msg].result_a.len() || count.result_b > hm[msg].result_b.len()
+            }));
 
             hm
-        }
+        };
 
-        DiffResult {
-            stdout_diff: out_diffs,
+        let mut result = DiffResult {
             err_diff: propagate_msg(rep.err_eq),
             wrn_diff: propagate_msg(rep.warn_eq),
             trc_diff: propagate_msg(rep.trace_eq),
+            res_diff: propagate_msg(rep.result_eq),
+            stdout_diff: self.stdout_diff,
+            stdout_diff_count: rep.stdout_eq.map_or(0, |stdout| stdout.count),
+            ast_diff: Vec::new(),
+            file_outcomes: Vec::new(),
+            labels: Labels::default(),
+            reworded: rep.reworded,
+            truncated,
+            raw_messages,
+            files_scanned: None,
+            perf_diff: Vec::new(),
+            one_sided: Default::default(),
+            provenance: None,
+        };
+        result.one_sided = one_sided_messages(rep.emitted, &result.messages());
+        result
+    }
+}
+
+/// One of the ways in which the outputs of two parsers can differ
+#[derive(enumset::EnumSetType, Debug)]
+pub enum Dimension {
+    Pass,
+    Exit,
+    Stdout,
+    Err,
+    Warn,
+    Trace,
+    Result,
+}
+
+impl FromStr for Dimension {
+    type Err = ();
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "pass" => Ok(Dimension::Pass),
+            "exit" => Ok(Dimension::Exit),
+            "stdout" => Ok(Dimension::Stdout),
+            "err" => Ok(Dimension::Err),
+            "warn" => Ok(Dimension::Warn),
+            "trace" => Ok(Dimension::Trace),
+            "result" => Ok(Dimension::Result),
+            _ => Err(()),
         }
     }
 }
 
-pub async fn diff_parsers(
-    folder: PathBuf,
-    nix_a: PathBuf,
-    nix_b: PathBuf,
-) -> color_eyre::Result<DiffResult> {
-    let files = walkdir::WalkDir::new(folder)
-        .follow_links(false)
+/// How many files are diffed at the same time
+const MAX_CONCURRENT_FILES: usize = 10;
+
+/// Directories that usually only contain vendored or generated files
+const DEFAULT_EXCLUDED_DIRS: &[&str] = &["node_modules", ".git", "result", "_sources"];
+
+/// Initial size of the buffers capturing the parsers' output
+const DEFAULT_OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct DiffOptions {
+    /// Additionally skip directories with this name. Can be given multiple times
+    #[arg(long)]
+    pub exclude_dir: Vec<String>,
+    /// Don't skip the directories excluded by default (`node_modules`, `.git`, `result`, `_sources`)
+    #[arg(long)]
+    pub no_default_excludes: bool,
+    /// Only diff files at most this many directories deep, counting the folder itself:
+    /// `1` only diffs the files directly in it. Unlimited by default
+    #[arg(long)]
+    pub max_depth: Option<usize>,
+    /// Find all files once up front to count them, so that the progress file has a total.
+    /// Doesn't apply to archives, which would have to be unpacked twice
+    #[arg(long)]
+    pub count_first: bool,
+    /// Walk directories in name order, and merge the diffs of the files in that order instead of
+    /// as they finish. That way the positions kept by `--max-positions` and the stdout samples
+    /// are the same on every run, but a slow file holds up merging the ones after it
+    #[arg(long)]
+    pub deterministic: bool,
+    /// Only diff the files of this class: `flake` for `flake.nix` files, or `regular` for all
+    /// other `.nix` files
+    #[arg(long, value_name = "CLASS", value_parser = |s: &str| FileClass::from_str(s).map_err(|()| format!("Invalid file class '{}'", s)))]
+    pub file_class: Option<FileClass>,
+    /// Name the local Nix binaries are executed as, which selects the mode of a multi-call `nix`.
+    /// Defaults to `nix-instantiate`; an empty string keeps the binary's own name
+    #[arg(long)]
+    pub arg0: Option<String>,
+    /// Only diff files tracked by git, when the folder is within a git repository
+    #[arg(long)]
+    pub git_tracked: bool,
+    /// Pass this extra argument to both parsers, after the fixed ones. Can be given multiple times,
+    /// e.g. `--nix-arg -I --nix-arg nixpkgs=/path`. The log format can't be changed
+    #[arg(long, allow_hyphen_values = true, value_parser = parse_nix_arg)]
+    pub nix_arg: Vec<String>,
+    /// Only diff the files that changed between two git revisions, e.g. `main..HEAD`
+    #[arg(long, value_name = "REV_A..REV_B")]
+    pub changed_between: Option<String>,
+    /// Give up on a file if a parser takes longer than this many seconds
+    #[arg(long)]
+    pub timeout: Option<u64>,
+    /// Also report the files where one parser took more than this many times as long as the
+    /// other, to find performance regressions. Parses under 100ms are too noisy and never count
+    #[arg(long, value_name = "RATIO")]
+    pub perf_ratio: Option<f64>,
+    /// Fail instead of only warning when there are no `.nix` files to diff
+    #[arg(long)]
+    pub require_files: bool,
+    /// Abort if more than a tenth of the `@nix` lines of one of the parsers can't be parsed,
+    /// instead of silently skipping them
+    #[arg(long)]
+    pub fail_on_parse_error: bool,
+    /// Abort instead of warning when both sides are the same Nix, by their path or `--version`
+    #[arg(long)]
+    pub strict: bool,
+    /// Write a `<file>\t<diverged>` line for every diffed file to this file, for scripts like a
+    /// `git bisect run` oracle. `diverged` is `true`, `false`, or `error` if the file couldn't be diffed.
+    /// With this, `nix-parse` also exits with a non-zero status when any file diverged
+    #[arg(long)]
+    pub files_tsv: Option<PathBuf>,
+    /// Keep the outputs of both parsers for every file they differ on, as
+    /// `<DIR>/<file>.{a,b}.{stdout,stderr,exit}`. `import-outputs` can compare them again later
+    #[arg(long, value_name = "DIR")]
+    pub save_raw: Option<PathBuf>,
+    /// Run local parsers with only a fixed, minimal environment instead of flaker's own, so that
+    /// `NIX_PATH`, `$HOME` and the user's Nix config can't make a difference between machines.
+    /// `ssh` needs the environment for logging in, so remote parsers are unaffected
+    #[arg(long)]
+    pub clean_env: bool,
+    /// Treat Nix binaries given as attribute names like `nixVersions.nix_2_24` as Nixpkgs packages,
+    /// and build them with `nix build` to use their `nix-instantiate`. Paths stay paths
+    #[arg(long)]
+    pub parsers_from_nixpkgs: bool,
+    /// Initial size in bytes of the buffers capturing the parsers' output.
+    /// Buffers are reused between files
+    #[arg(long, default_value_t = DEFAULT_OUTPUT_BUFFER_SIZE)]
+    pub output_buffer_size: usize,
+    /// Sort the bindings of attribute sets in the parsed AST before comparing,
+    /// so that only differences beyond their order are reported
+    #[arg(long)]
+    pub canonicalize_ast: bool,
+    /// Also compare differing stdouts as ASTs, and record which of their nodes were added,
+    /// removed or changed, by their path. Attribute sets are compared by their bindings,
+    /// so that their order doesn't matter
+    #[arg(long)]
+    pub ast_diff: bool,
+    /// Remove this prefix from the files of positions, so that locations in checkouts at
+    /// different places compare equal. Can be given multiple times
+    #[arg(long)]
+    pub strip_prefix: Vec<PathBuf>,
+    /// Compare messages missing on one side as one if their wording is at least this similar,
+    /// from 0 (anything goes) to 1 (identical). Pairs get reported as reworded
+    #[arg(long)]
+    pub reword_threshold: Option<f64>,
+    /// Record positions relative to the scanned folder, so that results from different
+    /// checkout locations can be compared
+    #[arg(long)]
+    pub relative_positions: bool,
+    /// Also record the original texts of messages that were simplified before comparing
+    #[arg(long)]
+    pub keep_raw_messages: bool,
+    /// Compare all occurrences of a message as one, over all files. With `false`, messages
+    /// are compared per file, so that rewordings are only paired within a file and
+    /// `--max-positions` samples each file. Uses more memory while diffing
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_name = "BOOL")]
+    pub dedup_across_files: bool,
+    /// Keep at most this many positions per message, and only count the others.
+    /// Bounds memory use for messages that appear in a huge number of files
+    #[arg(long)]
+    pub max_positions: Option<usize>,
+    /// Only report files that differ in this way, and ignore all other differences.
+    /// Can be given multiple times. One of `pass`, `exit`, `stdout`, `err`, `warn`, `trace`, `result`
+    #[arg(long, value_parser = |s: &str| Dimension::from_str(s).map_err(|()| format!("Invalid dimension '{}'", s)))]
+    pub only: Vec<Dimension>,
+    /// How to write positions in the result: `string` for `file:line:column`,
+    /// or `json-pointer` for `{file, line, col}` objects
+    #[arg(long, default_value = "string", value_parser = |s: &str| PositionsFormat::from_str(s).map_err(|()| format!("Invalid positions format '{}'", s)))]
+    pub positions_format: PositionsFormat,
+    /// Name of the first Nix in reports. Defaults to the name of its binary
+    #[arg(long)]
+    pub label_a: Option<String>,
+    /// Name of the second Nix in reports. Defaults to the name of its binary
+    #[arg(long)]
+    pub label_b: Option<String>,
+}
+
+/// The same defaults as on the command line
+impl Default for DiffOptions {
+    fn default() -> DiffOptions {
+        DiffOptions {
+            exclude_dir: Vec::new(),
+            no_default_excludes: false,
+            max_depth: None,
+            count_first: false,
+            deterministic: false,
+            file_class: None,
+            arg0: None,
+            git_tracked: false,
+            nix_arg: Vec::new(),
+            changed_between: None,
+            timeout: None,
+            perf_ratio: None,
+            require_files: false,
+            fail_on_parse_error: false,
+            strict: false,
+            files_tsv: None,
+            save_raw: None,
+            clean_env: false,
+            parsers_from_nixpkgs: false,
+            output_buffer_size: DEFAULT_OUTPUT_BUFFER_SIZE,
+            canonicalize_ast: false,
+            ast_diff: false,
+            strip_prefix: Vec::new(),
+            reword_threshold: None,
+            relative_positions: false,
+            keep_raw_messages: false,
+            dedup_across_files: true,
+            max_positions: None,
+            only: Vec::new(),
+            positions_format: PositionsFormat::String,
+            label_a: None,
+            label_b: None,
+        }
+    }
+}
+
+fn parse_nix_arg(arg: &str) -> Result<String, String> {
+    // Parsing the messages depends on `internal-json`
+    if arg.starts_with("--log-format") {
+        return Err("The log format can't be changed".into());
+    }
+    Ok(arg.to_string())
+}
+
+impl DiffOptions {
+    fn is_selected(&self, dimension: Dimension) -> bool {
+        self.only.is_empty() || self.only.contains(&dimension)
+    }
+
+    /// Whether the file at `path` is of the `--file-class` to diff
+    fn is_selected_file(&self, path: &Path) -> bool {
+        self.file_class
+            .is_none_or(|class| FileClass::of(path) == class)
+    }
+
+    fn is_excluded_dir(&self, name: &str) -> bool {
+        (!self.no_default_excludes && DEFAULT_EXCLUDED_DIRS.contains(&name))
+            || self.exclude_dir.iter().any(|dir| dir == name)
+    }
+
+    /// Whether to skip the file at `path` relative to the scanned folder,
+    /// as it is in an excluded directory or too deep
+    fn is_excluded_path(&self, path: &Path) -> bool {
+        let dirs = path
+            .parent()
+            .into_iter()
+            .flat_map(Path::components)
+            .filter(|c| matches!(c, std::path::Component::Normal(_)));
+        let mut depth = 1;
+        for dir in dirs {
+            if self.is_excluded_dir(&dir.as_os_str().to_string_lossy()) {
+                return true;
+            }
+            depth += 1;
+        }
+        self.max_depth.is_some_and(|max| depth > max)
+    }
+}
+
+/// Lazily find all `.nix` files below `folder`
+fn walk_files<'a>(folder: &Path, options: &'a DiffOptions) -> impl Iterator<Item = PathBuf> + 'a {
+    let mut walk = walkdir::WalkDir::new(folder);
+    if let Some(max_depth) = options.max_depth {
+        walk = walk.max_depth(max_depth);
+    }
+    if options.deterministic {
+        walk = walk.sort_by_file_name();
+    }
+    walk.follow_links(false)
         .follow_root_links(true)
         .into_iter()
+        // Never exclude the root, the user asked for it explicitly
+        .filter_entry(|e| {
+            e.depth() == 0
+                || !e.file_type().is_dir()
+                || !options.is_excluded_dir(&e.file_name().to_string_lossy())
+        })
         .filter_map(|res| match res {
             Ok(e) => Some(e),
             Err(err) => {
@@ -367,19 +2167,1284 @@ pub async fn diff_parsers(
                     .to_str()
                     .expect("UTF-8 file paths only please")
                     .ends_with(".nix")
+                && options.is_selected_file(e.path())
+        })
+        .map(walkdir::DirEntry::into_path)
+}
+
+/// Find all `.nix` files below `folder` that are tracked by git, which takes `.gitignore` into account.
+/// Returns `None` if `folder` is not within a git repository.
+async fn git_tracked_files(
+    folder: &Path,
+    options: &DiffOptions,
+) -> color_eyre::Result<Option<Vec<PathBuf>>> {
+    let output = tokio::process::Command::new("git")
+        .arg("-C")
+        .arg(folder)
+        .args(["ls-files", "-z", "--", "*.nix"])
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .wrap_err("Failed to run `git ls-files`")?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(git_listed_files(folder, &output.stdout, options)))
+}
+
+/// Find all `.nix` files below `folder` that changed between two revisions, given as `REV_A..REV_B`.
+/// The files are diffed as they are in the working tree.
+async fn git_changed_files(
+    folder: &Path,
+    revisions: &str,
+    options: &DiffOptions,
+) -> color_eyre::Result<Vec<PathBuf>> {
+    let output = tokio::process::Command::new("git")
+        .arg("-C")
+        .arg(folder)
+        .args([
+            "diff",
+            "--name-only",
+            "--relative",
+            "-z",
+            revisions,
+            "--",
+            "*.nix",
+        ])
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .wrap_err("Failed to run `git diff`")?;
+    if !output.status.success() {
+        color_eyre::eyre::bail!(
+            "`git diff {}` failed: {}",
+            revisions,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(git_listed_files(folder, &output.stdout, options))
+}
+
+/// Turn NUL-separated paths relative to `folder`, as printed by git, into existing files
+fn git_listed_files(folder: &Path, stdout: &[u8], options: &DiffOptions) -> Vec<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+
+    stdout
+        .split(|b| *b == 0)
+        .map(|path| Path::new(OsStr::from_bytes(path)))
+        .filter(|path| {
+            !path.as_os_str().is_empty()
+                && !options.is_excluded_path(path)
+                && options.is_selected_file(path)
+        })
+        .map(|path| folder.join(path))
+        // Tracked files may have been deleted in the working tree
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// Read the output of a parser captured earlier, from `<prefix>.stdout`, `<prefix>.stderr` and
+/// `<prefix>.exit` (containing the exit code). Missing files count as empty output and success.
+fn read_captured_output(prefix: &Path) -> color_eyre::Result<Output> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let read = |extension: &str| -> color_eyre::Result<Option<Vec<u8>>> {
+        let mut path = prefix.as_os_str().to_owned();
+        path.push(".");
+        path.push(extension);
+        let path = PathBuf::from(path);
+        match std::fs::read(&path) {
+            Ok(content) => Ok(Some(content)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).wrap_err_with(|| format!("Failed to read {}", path.display())),
+        }
+    };
+    let (stdout, stderr, exit) = (read("stdout")?, read("stderr")?, read("exit")?);
+    if stdout.is_none() && stderr.is_none() && exit.is_none() {
+        color_eyre::eyre::bail!(
+            "No captured output found at {}.{{stdout,stderr,exit}}",
+            prefix.display()
+        );
+    }
+    let code: i32 = match exit {
+        Some(exit) => String::from_utf8_lossy(&exit)
+            .trim()
+            .parse()
+            .wrap_err_with(|| format!("Invalid exit code in {}.exit", prefix.display()))?,
+        None => 0,
+    };
+    Ok(Output {
+        // Wait statuses carry the exit code in the second byte
+        status: std::process::ExitStatus::from_raw(code << 8),
+        stdout: stdout.unwrap_or_default(),
+        stderr: stderr.unwrap_or_default(),
+    })
+}
+
+/// Write the output of a parser the way [`read_captured_output`] reads it
+fn save_captured_output(prefix: &Path, output: &Output) -> std::io::Result<()> {
+    if let Some(parent) = prefix.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let write = |extension: &str, content: &[u8]| {
+        let mut path = prefix.as_os_str().to_owned();
+        path.push(".");
+        path.push(extension);
+        std::fs::write(path, content)
+    };
+    write("stdout", &output.stdout)?;
+    write("stderr", &output.stderr)?;
+    // Killed by a signal, which no Nix exits with on its own
+    write(
+        "exit",
+        format!("{}\n", output.status.code().unwrap_or(-1)).as_bytes(),
+    )
+}
+
+/// Diff outputs captured earlier as if they came from running the parsers on `file_label`
+pub fn diff_captured(
+    prefix_a: &Path,
+    prefix_b: &Path,
+    file_label: PathBuf,
+    options: &DiffOptions,
+) -> color_eyre::Result<DiffResult> {
+    let labels = Labels::new(
+        options.label_a.clone(),
+        options.label_b.clone(),
+        base_name(prefix_a),
+        base_name(prefix_b),
+    );
+    let result_a = read_captured_output(prefix_a)?;
+    let result_b = read_captured_output(prefix_b)?;
+    let mut merger = DiffMerger::new(options.max_positions);
+    let stats = LineStats::default();
+    let mut ast_diff = Vec::new();
+    let outcome = match compare_outputs(&file_label, result_a, result_b, options, &stats) {
+        Ok(None) => None,
+        Ok(Some(mut diff)) => {
+            if !diff.ast_eq.is_empty() {
+                ast_diff.push((file_label.clone(), std::mem::take(&mut diff.ast_eq)));
+            }
+            merger.add(diff);
+            Some(FileOutcome::Diff)
+        }
+        Err(outcome) => Some(outcome),
+    };
+    let mut result = merger.finish();
+    result.ast_diff = ast_diff;
+    result.file_outcomes = outcome
+        .map(|outcome| (file_label, outcome))
+        .into_iter()
+        .collect();
+    result.labels = labels;
+    result.files_scanned = Some(1);
+    Ok(result)
+}
+
+/// Rewrites of message texts, applied in order. Each replaces all matches of its `pattern`
+/// with its `replacement`, which can refer to capture groups like `$1` or `${name}`
+pub struct SimplifyRules(Vec<(regex::Regex, String)>);
+
+impl SimplifyRules {
+    /// Load a JSON list of `{ "pattern": <regex>, "replacement": <string> }` rules
+    pub fn from_path(path: &Path) -> color_eyre::Result<SimplifyRules> {
+        #[derive(Deserialize)]
+        struct Rule {
+            pattern: String,
+            replacement: String,
+        }
+        let rules: Vec<Rule> = serde_json::from_reader(std::io::BufReader::new(
+            std::fs::File::open(path)
+                .wrap_err_with(|| format!("Failed to open {}", path.display()))?,
+        ))
+        .wrap_err_with(|| format!("Failed to parse simplification rules {}", path.display()))?;
+        rules
+            .into_iter()
+            .enumerate()
+            .map(
+                |(
+                    i,
+                    Rule {
+                        pattern,
+                        replacement,
+                    },
+                )| {
+                    regex::Regex::new(&pattern)
+                        .wrap_err_with(|| {
+                            format!("Invalid pattern '{}' of rule {}", pattern, i + 1)
+                        })
+                        .map(|re| (re, replacement))
+                },
+            )
+            .collect::<color_eyre::Result<_>>()
+            .map(SimplifyRules)
+    }
+
+    fn apply(&self, msg: &Message) -> Message {
+        let mut simplified = msg.to_string();
+        for (re, replacement) in &self.0 {
+            simplified = re
+                .replace_all(&simplified, replacement.as_str())
+                .into_owned();
+        }
+        if *simplified == **msg {
+            msg.clone()
+        } else {
+            simplified.into()
+        }
+    }
+}
+
+/// Re-key the messages of an existing result through `rules`, merging the positions of messages
+/// that become the same. Positions that end up on both sides of a message are no divergence
+/// anymore and are dropped, as are the messages left without any
+pub fn renormalize(mut result: DiffResult, rules: &SimplifyRules) -> DiffResult {
+    let mut emitted: HashMap<Message, Diff<bool>> = HashMap::new();
+    for msg in result.messages() {
+        let by = result.emitted_by(msg);
+        let entry = emitted.entry(rules.apply(msg)).or_default();
+        entry.result_a |= by.result_a;
+        entry.result_b |= by.result_b;
+    }
+    let rekey = |occurrences: MessageOccurrences| {
+        let mut merged: MessageOccurrences = HashMap::new();
+        for (msg, diff) in occurrences {
+            let entry = merged.entry(rules.apply(&msg)).or_default();
+            entry.result_a.extend(diff.result_a);
+            entry.result_b.extend(diff.result_b);
+        }
+        merged.retain(|_, diff| {
+            let both: HashSet<Position> = diff
+                .result_a
+                .intersection(&diff.result_b)
+                .cloned()
+                .collect();
+            diff.result_a.retain(|pos| !both.contains(pos));
+            diff.result_b.retain(|pos| !both.contains(pos));
+            !diff.result_a.is_empty() || !diff.result_b.is_empty()
+        });
+        merged
+    };
+    result.err_diff = rekey(std::mem::take(&mut result.err_diff));
+    result.wrn_diff = rekey(std::mem::take(&mut result.wrn_diff));
+    result.trc_diff = rekey(std::mem::take(&mut result.trc_diff));
+    result.res_diff = rekey(std::mem::take(&mut result.res_diff));
+
+    result.reworded = std::mem::take(&mut result.reworded)
+        .into_iter()
+        .map(|pair| Diff {
+            result_a: rules.apply(&pair.result_a),
+            result_b: rules.apply(&pair.result_b),
+        })
+        // Rewording that the rules take care of now
+        .filter(|pair| pair.result_a != pair.result_b)
+        .collect();
+    // Totals of merged messages add up, though they overcount positions dropped above
+    let mut truncated: HashMap<Message, Diff<usize>> = HashMap::new();
+    for (msg, counts) in std::mem::take(&mut result.truncated) {
+        let entry = truncated.entry(rules.apply(&msg)).or_default();
+        entry.result_a += counts.result_a;
+        entry.result_b += counts.result_b;
+    }
+    let remaining: HashSet<&Message> = [
+        &result.err_diff,
+        &result.wrn_diff,
+        &result.trc_diff,
+        &result.res_diff,
+    ]
+    .into_iter()
+    .flat_map(HashMap::keys)
+    .collect();
+    let one_sided = one_sided_messages(emitted, &remaining);
+    truncated.retain(|msg, _| remaining.contains(msg));
+    let mut raw_messages: HashMap<Message, HashSet<Message>> = HashMap::new();
+    for (msg, raw) in std::mem::take(&mut result.raw_messages) {
+        let simplified = rules.apply(&msg);
+        if remaining.contains(&simplified) {
+            raw_messages.entry(simplified).or_default().extend(raw);
+        }
+    }
+    result.truncated = truncated;
+    result.raw_messages = raw_messages;
+    result.one_sided = one_sided;
+    result
+}
+
+/// Whether `path` is a tarball whose `.nix` files can be diffed like a folder
+fn is_archive(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    path.is_file()
+        && [".tar", ".tar.gz", ".tgz"]
+            .iter()
+            .any(|ext| name.ends_with(ext))
+}
+
+/// Unpack the `.nix` files of a tarball into `dir` one at a time, only shortly before they are
+/// diffed, instead of extracting the whole archive up front. `dir` is only removed once unpacking
+/// stopped as well, which it does at the next file once the stream is dropped
+fn unpack_archive(
+    archive: PathBuf,
+    dir: Arc<tempfile::TempDir>,
+    options: DiffOptions,
+) -> impl futures::Stream<Item = PathBuf> {
+    // Async, so that waiting for the next file doesn't block the diffs already running
+    let (tx, rx) = tokio::sync::mpsc::channel(MAX_CONCURRENT_FILES);
+    std::thread::spawn(move || {
+        let unpack = || -> std::io::Result<()> {
+            let file = std::fs::File::open(&archive)?;
+            let reader: Box<dyn std::io::Read> = if archive.extension() == Some("tar".as_ref()) {
+                Box::new(file)
+            } else {
+                Box::new(flate2::read::GzDecoder::new(file))
+            };
+            let mut tar = tar::Archive::new(reader);
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                let path = entry.path()?.into_owned();
+                if !entry.header().entry_type().is_file()
+                    || !path.to_string_lossy().ends_with(".nix")
+                    || options.is_excluded_path(&path)
+                    || !options.is_selected_file(&path)
+                {
+                    continue;
+                }
+                // Returns false for paths that would escape `dir`
+                if entry.unpack_in(dir.path())? && tx.blocking_send(dir.path().join(&path)).is_err()
+                {
+                    break;
+                }
+            }
+            Ok(())
+        };
+        if let Err(err) = unpack() {
+            tracing::warn!(archive = %archive.display(), err = %err, "Failed to read archive");
+        }
+        // Before the sender goes away, so that the directory is gone once the stream ends
+        drop(dir);
+    });
+    futures::stream::unfold(rx, |mut rx| async move {
+        let file = rx.recv().await?;
+        Some((file, rx))
+    })
+}
+
+/// Find the files to diff in `folder`, according to the git options
+async fn find_files<'a>(
+    folder: &Path,
+    options: &'a DiffOptions,
+) -> color_eyre::Result<Box<dyn Iterator<Item = PathBuf> + 'a>> {
+    if let Some(revisions) = &options.changed_between {
+        return Ok(Box::new(
+            git_changed_files(folder, revisions, options)
+                .await?
+                .into_iter(),
+        ));
+    }
+    if options.git_tracked {
+        match git_tracked_files(folder, options).await? {
+            Some(files) => return Ok(Box::new(files.into_iter())),
+            None => {
+                tracing::warn!(folder = %folder.display(), "Not a git repository, scanning all files")
+            }
+        }
+    }
+    Ok(Box::new(walk_files(folder, options)))
+}
+
+pub async fn diff_parsers(
+    folder: PathBuf,
+    nix_a: NixCommand,
+    nix_b: NixCommand,
+    mut options: DiffOptions,
+    progress: &Progress,
+) -> color_eyre::Result<DiffResult> {
+    check_distinct(&nix_a, &nix_b, options.strict).await?;
+    let archive_dir = if is_archive(&folder) {
+        Some(Arc::new(
+            // Recognizable in case it is left behind by a crash
+            tempfile::Builder::new()
+                .prefix("flaker-archive-")
+                .tempdir()
+                .wrap_err("Failed to create a directory to unpack into")?,
+        ))
+    } else {
+        None
+    };
+    for nix in [&nix_a, &nix_b] {
+        if let NixCommand::Recorded(corpus) = nix {
+            // The corpus has positions below the folder it was recorded in
+            options.strip_prefix.push(corpus.folder.clone());
+            options.relative_positions = true;
+        }
+    }
+    if let Some(dir) = &archive_dir {
+        // Name files by their path within the archive
+        options.strip_prefix.push(dir.path().to_owned());
+    } else if options.relative_positions {
+        // Nix reports absolute paths, while the fallback is the path as it was walked
+        if let Ok(canonical) = folder.canonicalize() {
+            options.strip_prefix.push(canonical);
+        }
+        options.strip_prefix.push(folder.clone());
+    }
+    let root = match &archive_dir {
+        Some(dir) => dir.path().to_owned(),
+        None => folder.clone(),
+    };
+    let files = match &archive_dir {
+        Some(dir) => unpack_archive(folder.clone(), dir.clone(), options.clone()).boxed_local(),
+        None => futures::stream::iter(find_files(&folder, &options).await?).boxed_local(),
+    };
+    // Files are discovered lazily, so there is only a total when they are found twice
+    let total = if options.count_first && archive_dir.is_none() {
+        let total = find_files(&folder, &options).await?.count();
+        tracing::info!(total, "Counted files to diff");
+        Some(total as u64)
+    } else {
+        None
+    };
+    progress.stage("diffing", total);
+
+    let buffers = BufferPool::new(options.output_buffer_size);
+    let stats = LineStats::default();
+    let outcomes = files.map(|file| {
+        let nix_a = &nix_a;
+        let nix_b = &nix_b;
+        let options = &options;
+        let buffers = &buffers;
+        let archive_dir = &archive_dir;
+        let root = &root;
+        let stats = &stats;
+        async move {
+            let res = diff_file(&file, root, nix_a, nix_b, options, buffers, stats).await;
+            if archive_dir.is_some() {
+                // Keep the unpacked part of the archive small
+                let _ = std::fs::remove_file(&file);
+            }
+            progress.inc();
+            (file, res)
+        }
+    });
+    // Which samples the merger keeps depends on the order it sees the files in
+    let outcomes = if options.deterministic {
+        outcomes.buffered(MAX_CONCURRENT_FILES).boxed_local()
+    } else {
+        outcomes
+            .buffer_unordered(MAX_CONCURRENT_FILES)
+            .boxed_local()
+    };
+    futures::pin_mut!(outcomes);
+
+    // Merge as the results come in, so that only the merged positions are kept around
+    let mut merger = DiffMerger::new(options.max_positions);
+    let mut ast_diff = Vec::new();
+    let mut file_outcomes = Vec::new();
+    let mut perf_diff = Vec::new();
+    let mut verdicts = Vec::new();
+    let mut scanned = 0;
+    while let Some((file, res)) = outcomes.next().await {
+        scanned += 1;
+        if options.fail_on_parse_error {
+            stats.check([&nix_a, &nix_b], MIN_PARSE_ERROR_SAMPLE)?;
+        }
+        let verdict_file = options
+            .files_tsv
+            .is_some()
+            .then(|| strip_prefixes(&file, &options.strip_prefix).to_owned());
+        let verdict = match res {
+            Ok(None) => "false",
+            Ok(Some(mut diff)) => {
+                if let Some(perf_eq) = diff.perf_eq.take() {
+                    perf_diff.push((file.clone(), perf_eq));
+                }
+                if !diff.ast_eq.is_empty() {
+                    ast_diff.push((file.clone(), std::mem::take(&mut diff.ast_eq)));
+                }
+                // Only slower parsing doesn't make the outputs diverge
+                if diff.is_empty() {
+                    "false"
+                } else {
+                    merger.add(diff);
+                    file_outcomes.push((file, FileOutcome::Diff));
+                    "true"
+                }
+            }
+            Err(outcome) => {
+                tracing::warn!(file = %file.display(), ?outcome, "Failed to diff file");
+                file_outcomes.push((file, outcome));
+                "error"
+            }
+        };
+        if let Some(file) = verdict_file {
+            verdicts.push((file, verdict));
+        }
+    }
+    if options.fail_on_parse_error {
+        stats.check([&nix_a, &nix_b], 0)?;
+    }
+    if scanned == 0 {
+        // Otherwise indistinguishable from a folder without any differences
+        if options.require_files {
+            color_eyre::eyre::bail!("No `.nix` files found in {}", folder.display());
+        }
+        tracing::warn!(folder = %folder.display(), "No `.nix` files found, is this the right folder?");
+    }
+    for (file, _) in &mut ast_diff {
+        *file = strip_prefixes(file, &options.strip_prefix).to_owned();
+    }
+    ast_diff.sort_by(|a, b| a.0.cmp(&b.0));
+    for (file, _) in &mut file_outcomes {
+        *file = strip_prefixes(file, &options.strip_prefix).to_owned();
+    }
+    file_outcomes.sort_by(|a, b| a.0.cmp(&b.0));
+    for (file, _) in &mut perf_diff {
+        *file = strip_prefixes(file, &options.strip_prefix).to_owned();
+    }
+    perf_diff.sort_by(|a, b| a.0.cmp(&b.0));
+    if let Some(path) = &options.files_tsv {
+        verdicts.sort();
+        let tsv: String = verdicts
+            .iter()
+            .map(|(file, verdict)| format!("{}\t{}\n", file.display(), verdict))
+            .collect();
+        std::fs::write(path, tsv)
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+    }
+
+    let mut result = merger.finish();
+    result.ast_diff = ast_diff;
+    result.file_outcomes = file_outcomes;
+    result.files_scanned = Some(scanned);
+    result.perf_diff = perf_diff;
+    result.labels = Labels::new(
+        options.label_a.clone(),
+        options.label_b.clone(),
+        nix_a.base_name(),
+        nix_b.base_name(),
+    );
+    tracing::info!(?result);
+    Ok(result)
+}
+
+/// Complain when both sides are the same Nix, which can only ever give an empty result
+async fn check_distinct(
+    nix_a: &NixCommand,
+    nix_b: &NixCommand,
+    strict: bool,
+) -> color_eyre::Result<()> {
+    let problem = if nix_a.is_same_binary(nix_b) {
+        format!("`{}` and `{}` are the same binary", nix_a, nix_b)
+    } else {
+        match (nix_a.version().await, nix_b.version().await) {
+            (Some(a), Some(b)) if a == b => {
+                format!("`{}` and `{}` are the same version: {}", nix_a, nix_b, a)
+            }
+            _ => return Ok(()),
+        }
+    };
+    if strict {
+        color_eyre::eyre::bail!("{}, there is nothing to compare", problem);
+    }
+    tracing::warn!("{}, the comparison is likely pointless", problem);
+    Ok(())
+}
+
+/// The parser outputs of a reference run over a folder, to diff a single Nix against
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Corpus {
+    /// The Nix binary the outputs were recorded with
+    nix: PathBuf,
+    /// The folder the outputs were recorded in, which positions in them are below of
+    folder: PathBuf,
+    /// Outputs by the path of the file relative to `folder`
+    files: HashMap<PathBuf, RecordedOutput>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedOutput {
+    stdout: String,
+    stderr: String,
+    exit: i32,
+}
+
+impl Corpus {
+    pub fn load(path: &Path) -> color_eyre::Result<Corpus> {
+        let file = std::fs::File::open(path)
+            .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .wrap_err_with(|| format!("Failed to read the corpus {}", path.display()))
+    }
+
+    /// The recorded output of the file at `path` relative to the recorded folder
+    fn output(&self, path: &Path) -> Option<Output> {
+        use std::os::unix::process::ExitStatusExt;
+
+        self.files.get(path).map(|output| Output {
+            // Wait statuses carry the exit code in the second byte
+            status: std::process::ExitStatus::from_raw(output.exit << 8),
+            stdout: output.stdout.clone().into_bytes(),
+            stderr: output.stderr.clone().into_bytes(),
+        })
+    }
+}
+
+/// Run `nix` on all files in `folder` and record the outputs, to later diff another Nix against them
+pub async fn record_corpus(
+    folder: PathBuf,
+    nix: NixCommand,
+    options: DiffOptions,
+    progress: &Progress,
+) -> color_eyre::Result<Corpus> {
+    if is_archive(&folder) {
+        color_eyre::eyre::bail!(
+            "Recording a corpus of an archive is not supported, unpack it first"
+        );
+    }
+    if let NixCommand::Recorded(_) = nix {
+        color_eyre::eyre::bail!("Cannot record a corpus from another corpus");
+    }
+    progress.stage("recording", None);
+    let buffers = BufferPool::new(options.output_buffer_size);
+    let outputs = futures::stream::iter(find_files(&folder, &options).await?)
+        .map(|file| {
+            let (nix, folder, options, buffers) = (&nix, &folder, &options, &buffers);
+            async move {
+                let res = run_parser(nix, Side::A, &file, folder, options, buffers).await;
+                progress.inc();
+                (file, res)
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_FILES)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut files = HashMap::new();
+    for (file, res) in outputs {
+        let recorded = res.and_then(|output| {
+            Ok(RecordedOutput {
+                stdout: String::from_utf8(output.stdout)
+                    .map_err(|_| FileOutcome::NonUtf8 { side: Side::A })?,
+                stderr: String::from_utf8(output.stderr)
+                    .map_err(|_| FileOutcome::NonUtf8 { side: Side::A })?,
+                // Killed by a signal, which no Nix exits with on its own
+                exit: output.status.code().unwrap_or(-1),
+            })
         });
+        match recorded {
+            Ok(recorded) => {
+                let path = file.strip_prefix(&folder).unwrap_or(&file).to_owned();
+                files.insert(path, recorded);
+            }
+            Err(outcome) => {
+                tracing::warn!(file = %file.display(), ?outcome, "Failed to record file")
+            }
+        }
+    }
+    let nix = match nix {
+        NixCommand::Local(nix) | NixCommand::Ssh { nix, .. } => nix,
+        template @ NixCommand::Template(_) => template.to_string().into(),
+        NixCommand::Recorded(_) => unreachable!(),
+    };
+    Ok(Corpus {
+        nix,
+        folder: folder.canonicalize().unwrap_or(folder),
+        files,
+    })
+}
+
+/// Where along an ordered list of Nix versions the files first diverged
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BisectResult {
+    /// The compared Nix versions, in order
+    pub versions: Vec<String>,
+    /// File -> index of the first version whose output differs from the one before it.
+    /// Files that are the same for all versions are left out
+    pub first_divergence: std::collections::BTreeMap<PathBuf, usize>,
+    /// File -> why it could not be compared
+    pub errors: std::collections::BTreeMap<PathBuf, String>,
+}
 
-    let diffs = futures::stream::iter(files)
+/// Run all `nixes` on `file`, and find the first one whose output differs from its predecessor
+async fn first_divergence(
+    file: &Path,
+    root: &Path,
+    nixes: &[NixCommand],
+    options: &DiffOptions,
+    buffers: &BufferPool,
+    stats: &LineStats,
+) -> Result<Option<usize>, String> {
+    let outputs = futures::future::join_all(
+        nixes
+            .iter()
+            .map(|nix| run_parser(nix, Side::A, file, root, options, buffers)),
+    )
+    .await;
+    let outputs = nixes
+        .iter()
+        .zip(outputs)
+        .map(|(nix, output)| output.map_err(|outcome| format!("{}: {:?}", nix, outcome)))
+        .collect::<Result<Vec<_>, _>>()?;
+    for i in 1..outputs.len() {
+        let (before, after) = (outputs[i - 1].clone(), outputs[i].clone());
+        match compare_outputs(file, before, after, options, stats) {
+            Ok(None) => {}
+            Ok(Some(_)) => return Ok(Some(i)),
+            Err(outcome) => return Err(format!("{} → {}: {:?}", nixes[i - 1], nixes[i], outcome)),
+        }
+    }
+    for output in outputs {
+        buffers.give_back(output.stdout);
+        buffers.give_back(output.stderr);
+    }
+    Ok(None)
+}
+
+/// Whether `spec` looks like a Nixpkgs attribute rather than a path, an `ssh://` URL or a command
+pub fn is_nixpkgs_attr(spec: &str) -> bool {
+    !spec.is_empty()
+        && spec
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.+'".contains(c))
+        // A binary in the current directory
+        && !Path::new(spec).exists()
+}
+
+/// Build the Nixpkgs package `attr` with the `nix` on `PATH`, and find its `nix-instantiate`
+pub async fn build_nixpkgs_parser(attr: &str) -> color_eyre::Result<PathBuf> {
+    tracing::info!(attr, "Building Nix from Nixpkgs");
+    let output = tokio::process::Command::new("nix")
+        .args(["--extra-experimental-features", "nix-command flakes"])
+        .args(["build", "--no-link", "--print-out-paths"])
+        .arg(format!("nixpkgs#{}", attr))
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .await
+        .wrap_err("Failed to run `nix build`")?;
+    if !output.status.success() {
+        color_eyre::eyre::bail!("Building nixpkgs#{} failed with {}", attr, output.status);
+    }
+    // One line per output of the package, only one of which has the binaries
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|out| Path::new(out).join("bin").join(PARSE_ARG0))
+        .find(|nix| nix.exists())
+        .ok_or_else(|| color_eyre::eyre::eyre!("nixpkgs#{} has no bin/{}", attr, PARSE_ARG0))
+}
+
+/// Run an ordered list of Nix versions on all files in `folder`, and find for every file
+/// between which two adjacent versions its output first changed
+pub async fn bisect_parsers(
+    folder: PathBuf,
+    nixes: Vec<NixCommand>,
+    options: DiffOptions,
+    progress: &Progress,
+) -> color_eyre::Result<BisectResult> {
+    if is_archive(&folder) {
+        color_eyre::eyre::bail!("Bisecting an archive is not supported, unpack it first");
+    }
+    progress.stage("bisecting", None);
+    let buffers = BufferPool::new(options.output_buffer_size);
+    let stats = LineStats::default();
+    let outcomes = futures::stream::iter(find_files(&folder, &options).await?)
         .map(|file| {
-            let nix_a = &nix_a;
-            let nix_b = &nix_b;
-            async move { diff_file(file.path(), nix_a, nix_b).await }
+            let (nixes, folder, options) = (&nixes, &folder, &options);
+            let (buffers, stats) = (&buffers, &stats);
+            async move {
+                let res = first_divergence(&file, folder, nixes, options, buffers, stats).await;
+                progress.inc();
+                (file, res)
+            }
         })
-        .buffer_unordered(10)
-        .filter_map(|res| async move { res.unwrap_or_else(|_| None) })
-        .collect::<Vec<ParserDiff>>()
+        .buffer_unordered(MAX_CONCURRENT_FILES)
+        .collect::<Vec<_>>()
         .await;
-    let result = DiffResult::from(diffs);
-    tracing::info!(?result);
+
+    let mut result = BisectResult {
+        versions: nixes.iter().map(NixCommand::to_string).collect(),
+        first_divergence: Default::default(),
+        errors: Default::default(),
+    };
+    for (file, res) in outcomes {
+        let file = strip_prefixes(&file, &options.strip_prefix).to_owned();
+        match res {
+            Ok(None) => {}
+            Ok(Some(version)) => {
+                result.first_divergence.insert(file, version);
+            }
+            Err(error) => {
+                tracing::warn!(file = %file.display(), error, "Failed to bisect file");
+                result.errors.insert(file, error);
+            }
+        }
+    }
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_options_default_matches_cli() {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            options: DiffOptions,
+        }
+        let cli = <Cli as clap::Parser>::parse_from(["flaker"]);
+        assert_eq!(
+            format!("{:?}", cli.options),
+            format!("{:?}", DiffOptions::default())
+        );
+    }
+
+    fn position(file: &str, line: Option<u32>, column: Option<u32>) -> Position {
+        Position {
+            file: file.to_string(),
+            line,
+            column,
+        }
+    }
+
+    #[test]
+    fn position_from_string() {
+        let parse = |s: &str| Position::from(s.to_string());
+        assert_eq!(parse("a/b.nix:3:7"), position("a/b.nix", Some(3), Some(7)));
+        assert_eq!(parse("a/b.nix:3"), position("a/b.nix", Some(3), None));
+        assert_eq!(parse("a/b.nix"), position("a/b.nix", None, None));
+        for s in ["a/b.nix:3:7", "a/b.nix:3", "a/b.nix"] {
+            assert_eq!(parse(s).to_string(), s);
+        }
+    }
+
+    #[test]
+    fn position_from_string_with_colons_in_path() {
+        let parse = |s: &str| Position::from(s.to_string());
+        assert_eq!(
+            parse("/nix/store/x:y/default.nix:3:7"),
+            position("/nix/store/x:y/default.nix", Some(3), Some(7))
+        );
+        assert_eq!(parse("c:d.nix:12"), position("c:d.nix", Some(12), None));
+        assert_eq!(parse("c:d.nix"), position("c:d.nix", None, None));
+    }
+
+    #[test]
+    fn natural_order() {
+        use std::cmp::Ordering::*;
+        assert_eq!(natural_cmp("a2", "a10"), Less);
+        assert_eq!(natural_cmp("a10", "a2"), Greater);
+        assert_eq!(natural_cmp("file2.nix", "file10.nix"), Less);
+        assert_eq!(natural_cmp("a10", "b2"), Less);
+        assert_eq!(natural_cmp("a", "a1"), Less);
+        assert_eq!(natural_cmp("a1b", "a1b"), Equal);
+        assert!(position("dir2/x.nix", None, None) < position("dir10/x.nix", None, None));
+        assert!(position("x.nix", Some(2), None) < position("x.nix", Some(10), None));
+        assert!(position("x.nix", Some(2), Some(9)) < position("x.nix", Some(2), Some(10)));
+    }
+
+    #[test]
+    fn canonicalize_ast_ignores_binding_order() {
+        use canonical::canonicalize_ast;
+        assert_eq!(
+            canonicalize_ast("({ b = 1; a = { y = 2; x = 3; }; })"),
+            canonicalize_ast("({ a = { x = 3; y = 2; }; b = 1; })")
+        );
+        assert_ne!(
+            canonicalize_ast("({ a = 1; b = 2; })"),
+            canonicalize_ast("({ a = 2; b = 1; })")
+        );
+    }
+
+    #[test]
+    fn canonicalize_ast_keeps_strings_and_formals() {
+        use canonical::canonicalize_ast;
+        assert_ne!(
+            canonicalize_ast(r#"({ a = "x;b = 1"; })"#),
+            canonicalize_ast(r#"({ a = "x"; b = 1; })"#)
+        );
+        assert_ne!(
+            canonicalize_ast("({ a, b }: a)"),
+            canonicalize_ast("({ b, a }: a)")
+        );
+        assert_eq!(canonicalize_ast("(x: x)"), "(x: x)");
+    }
+
+    fn change(path: &str, a: Option<&str>, b: Option<&str>) -> AstChange {
+        AstChange {
+            path: path.to_string(),
+            node: Diff {
+                result_a: a.map(Message::from),
+                result_b: b.map(Message::from),
+            },
+        }
+    }
+
+    #[test]
+    fn ast_diff_reports_nodes_by_path() {
+        let changes = ast::diff(
+            "({ a = 1; b = [ 1 2 3 ]; c = (x: x); inherit d; })",
+            r#"({ b = [ 1 5 2 3 ]; c = (y: x); e = "s;{"; inherit d; })"#,
+        );
+        assert_eq!(
+            changes,
+            vec![
+                change("$[0].a", Some("1"), None),
+                change("$[0].b[1]", None, Some("5")),
+                change("$[0].c[0]", Some("x:"), Some("y:")),
+                change("$[0].e", None, Some(r#""s;{""#)),
+            ]
+        );
+    }
+
+    #[test]
+    fn ast_diff_ignores_binding_order() {
+        assert_eq!(
+            ast::diff("({ a = 1; b = 2; })", "({ b = 2; a = 1; })"),
+            vec![]
+        );
+        assert_eq!(ast::diff("(x: x)", "(x: x)"), vec![]);
+    }
+
+    #[test]
+    fn ast_diff_replaces_nodes_of_another_kind() {
+        assert_eq!(
+            ast::diff("({ a = [ 1 ]; })", "({ a = { b = 1; }; })"),
+            vec![change("$[0].a", Some("[ 1 ]"), Some("{ b = 1; }"))]
+        );
+    }
+
+    fn key(msg: &str) -> LogKey {
+        LogKey {
+            msg: msg.into(),
+            file: None,
+        }
+    }
+
+    fn comp_log(entries: &[(&str, &str)]) -> CompLog {
+        let mut log = CompLog::new();
+        for (msg, pos) in entries {
+            log.entry(key(msg))
+                .or_default()
+                .positions
+                .insert(Position::from(pos.to_string()));
+        }
+        log
+    }
+
+    /// Like [`comp_log`], but compared per file like with `--dedup-across-files false`
+    fn comp_log_per_file(entries: &[(&str, &str)]) -> CompLog {
+        let mut log = CompLog::new();
+        for (msg, pos) in entries {
+            let pos = Position::from(pos.to_string());
+            let key = LogKey {
+                file: Some(pos.file.clone()),
+                ..key(msg)
+            };
+            log.entry(key).or_default().positions.insert(pos);
+        }
+        log
+    }
+
+    #[test]
+    fn pair_reworded_renames_similar_messages() {
+        let log_a = comp_log(&[
+            ("undefined variable 'foo'", "a.nix:1:2"),
+            ("same", "a.nix:3"),
+        ]);
+        let mut log_b = comp_log(&[
+            ("undefined variable `foo`", "a.nix:1:2"),
+            ("same", "a.nix:3"),
+        ]);
+        let pairs = pair_reworded(&log_a, &mut log_b, 0.8);
+        assert_eq!(
+            pairs,
+            HashSet::from([Diff {
+                result_a: Message::from("undefined variable 'foo'"),
+                result_b: Message::from("undefined variable `foo`"),
+            }])
+        );
+        assert_eq!(log_a, log_b);
+    }
+
+    #[test]
+    fn pair_reworded_keeps_dissimilar_messages() {
+        let log_a = comp_log(&[("undefined variable 'foo'", "a.nix:1:2")]);
+        let mut log_b = comp_log(&[("syntax error, unexpected ')'", "a.nix:1:2")]);
+        assert!(pair_reworded(&log_a, &mut log_b, 0.8).is_empty());
+        assert!(log_b.contains_key(&key("syntax error, unexpected ')'")));
+    }
+
+    #[test]
+    fn pair_reworded_pairs_each_message_once() {
+        let log_a = comp_log(&[("warning: foo 1", "a.nix:1")]);
+        let mut log_b = comp_log(&[("warning: foo 2", "a.nix:1"), ("warning: foo 3", "a.nix:2")]);
+        assert_eq!(pair_reworded(&log_a, &mut log_b, 0.5).len(), 1);
+        assert_eq!(log_b.len(), 2);
+        assert!(log_b.contains_key(&key("warning: foo 1")));
+    }
+
+    #[test]
+    fn pair_reworded_stays_within_files() {
+        let log_a = comp_log_per_file(&[("warning: foo 1", "a.nix:1")]);
+        let mut log_b = comp_log_per_file(&[("warning: foo 2", "b.nix:1")]);
+        assert!(pair_reworded(&log_a, &mut log_b, 0.8).is_empty());
+        let mut log_b = comp_log_per_file(&[("warning: foo 2", "a.nix:1")]);
+        assert_eq!(pair_reworded(&log_a, &mut log_b, 0.8).len(), 1);
+    }
+
+    #[test]
+    fn dedup_per_file_keeps_messages() {
+        let stderr = [
+            r#"@nix {"action":"msg","level":1,"msg":"warning: hm","file":"a.nix","line":1}"#,
+            r#"@nix {"action":"msg","level":1,"msg":"warning: hm","file":"b.nix","line":2}"#,
+        ]
+        .join("\n");
+        let options = DiffOptions {
+            dedup_across_files: false,
+            ..DiffOptions::default()
+        };
+        let (_, warn, _, _) = parsing::split_stderr(
+            stderr,
+            Side::A,
+            Path::new("a.nix"),
+            &options,
+            &LineStats::default(),
+        );
+        assert_eq!(
+            warn,
+            comp_log_per_file(&[("warning: hm", "a.nix:1"), ("warning: hm", "b.nix:2")])
+        );
+
+        let mut merger = DiffMerger::new(None);
+        merger.add(ParserDiff {
+            warn_eq: Some(Diff::from(warn, CompLog::new())),
+            ..ParserDiff::default()
+        });
+        let result = merger.finish();
+        assert_eq!(
+            result.wrn_diff["warning: hm"].result_a,
+            HashSet::from([
+                position("a.nix", Some(1), None),
+                position("b.nix", Some(2), None)
+            ])
+        );
+    }
+
+    fn parse_with_errors(stderr: &str) -> (Vec<serde_json::Value>, Vec<(String, String)>) {
+        let mut errors = vec![];
+        let entries = parsing::parse_log_entries(stderr, |line, reason| {
+            errors.push((line.to_string(), reason))
+        });
+        let entries = entries
+            .into_iter()
+            .map(|entry| serde_json::to_value(entry).unwrap())
+            .collect();
+        (entries, errors)
+    }
+
+    #[test]
+    fn parse_log_entries_reports_malformed_json() {
+        let (entries, errors) = parse_with_errors(
+            "@nix {\"action\":\"msg\",\"level\":0,\"msg\":\"ok\"}\n@nix {\"action\":\"msg\",\n@nix [1, 2]\n",
+        );
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["msg"], "ok");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].0, "@nix {\"action\":\"msg\",");
+        for (_, reason) in &errors {
+            assert!(reason.starts_with("error parsing json"), "{}", reason);
+        }
+    }
+
+    #[test]
+    fn parse_log_entries_keeps_unknown_actions() {
+        let (entries, errors) =
+            parse_with_errors("@nix {\"action\":\"setPhase\",\"phase\":\"buildPhase\",\"id\":3}");
+        assert!(errors.is_empty());
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["action"], "setPhase");
+        assert_eq!(entries[0]["phase"], "buildPhase");
+        assert_eq!(entries[0]["id"], 3);
+    }
+
+    #[test]
+    fn parse_log_entries_drops_other_lines() {
+        let (entries, errors) = parse_with_errors(
+            "warning: not json\r\n\r\n@nix {\"action\":\"msg\",\"level\":1,\"msg\":\"w\"}\r\n\n   \n",
+        );
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["msg"], "w");
+        assert_eq!(
+            errors,
+            vec![(
+                "warning: not json".to_string(),
+                "not an `@nix` line".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn split_stderr_ignores_unknown_actions() {
+        let stderr = [
+            r#"@nix {"action":"msg","level":0,"msg":"error: boom","line":3,"column":1}"#,
+            r#"@nix {"action":"setPhase","phase":"buildPhase"}"#,
+            r#"@nix {"action":"start","id":1,"type":0}"#,
+            r#"@nix {"action":"msg","level":1,"msg":"warning: hm"}"#,
+            "garbage",
+        ]
+        .join("\n");
+        let stats = LineStats::default();
+        let (err, warn, trace, results) = parsing::split_stderr(
+            stderr,
+            Side::A,
+            Path::new("x.nix"),
+            &DiffOptions::default(),
+            &stats,
+        );
+        assert_eq!(
+            err.keys().map(|k| &*k.msg).collect::<Vec<_>>(),
+            ["error: boom"]
+        );
+        assert_eq!(
+            err[&key("error: boom")].positions,
+            HashSet::from([position("x.nix", Some(3), Some(1))])
+        );
+        assert_eq!(
+            warn.keys().map(|k| &*k.msg).collect::<Vec<_>>(),
+            ["warning: hm"]
+        );
+        assert!(trace.is_empty());
+        assert!(results.is_empty());
+        assert_eq!(stats.lines[0].load(std::sync::atomic::Ordering::Relaxed), 5);
+        assert_eq!(
+            stats.failed[0].load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+    }
+
+    fn fake_nix(dir: &Path, name: &str, warning: &str) -> NixCommand {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join(name);
+        let script = format!(
+            "#!/bin/sh\necho '@nix {{\"action\":\"msg\",\"level\":1,\"msg\":\"{}\"}}' >&2\necho '{}'\n",
+            warning, name
+        );
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        NixCommand::Local(path)
+    }
+
+    fn archive_dirs() -> HashSet<PathBuf> {
+        std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with("flaker-archive-"))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn archive_unpacking_leaves_no_temp_files() {
+        let work = tempfile::tempdir().unwrap();
+        let nix_a = fake_nix(work.path(), "nix-a", "warning A");
+        let nix_b = fake_nix(work.path(), "nix-b", "warning B");
+        let before = archive_dirs();
+
+        let archive = work.path().join("src.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(&archive).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut tar = tar::Builder::new(encoder);
+        for name in ["pkg/default.nix", "pkg/lib.nix", "README.md"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(2);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, name, &b"{}"[..]).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+        let result = diff_parsers(
+            archive,
+            nix_a.clone(),
+            nix_b.clone(),
+            DiffOptions::default(),
+            &Progress::new(None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            result.file_outcomes,
+            vec![
+                (PathBuf::from("pkg/default.nix"), FileOutcome::Diff),
+                (PathBuf::from("pkg/lib.nix"), FileOutcome::Diff),
+            ]
+        );
+        assert_eq!(archive_dirs(), before);
+
+        let corrupt = work.path().join("corrupt.tar.gz");
+        std::fs::write(&corrupt, "not a tarball").unwrap();
+        let options = DiffOptions {
+            require_files: true,
+            ..DiffOptions::default()
+        };
+        let result = diff_parsers(corrupt, nix_a, nix_b, options, &Progress::new(None)).await;
+        assert!(result.is_err());
+        assert_eq!(archive_dirs(), before);
+    }
+
+    #[test]
+    fn merge_counts_only_new_positions_as_dropped() {
+        let finds = |positions: &[&str]| Finds {
+            positions: positions
+                .iter()
+                .map(|pos| Position::from(pos.to_string()))
+                .collect(),
+            ..Finds::default()
+        };
+        let mut merged = finds(&["a.nix:1", "a.nix:2"]);
+        merged.merge(finds(&["a.nix:1", "a.nix:2", "a.nix:3"]), Some(2));
+        assert_eq!(merged.positions.len(), 2);
+        assert_eq!(merged.dropped, 1);
+        merged.merge(finds(&["a.nix:2", "a.nix:4"]), Some(2));
+        assert_eq!(merged.dropped, 2);
+    }
+
+    #[test]
+    fn reworded_only_of_selected_dimensions() {
+        let stderr = |msg: &str| {
+            format!(
+                r#"@nix {{"action":"msg","level":1,"msg":"{}","line":1}}"#,
+                msg
+            )
+        };
+        let options = DiffOptions {
+            reword_threshold: Some(0.8),
+            only: vec![Dimension::Err],
+            ..DiffOptions::default()
+        };
+        let diff = diff_stderr(
+            stderr("undefined variable 'foo'"),
+            stderr("undefined variable `foo`"),
+            Path::new("a.nix"),
+            &options,
+            &LineStats::default(),
+        );
+        assert!(diff.reworded.is_empty());
+        let options = DiffOptions {
+            only: vec![Dimension::Warn],
+            ..options
+        };
+        let diff = diff_stderr(
+            stderr("undefined variable 'foo'"),
+            stderr("undefined variable `foo`"),
+            Path::new("a.nix"),
+            &options,
+            &LineStats::default(),
+        );
+        assert_eq!(diff.reworded.len(), 1);
+    }
+}
@@ -0,0 +1,79 @@
+use crate::diffing::NixCommand;
+use crate::indexing::{build_client, NUR_REPOS_URL};
+use color_eyre::eyre::{bail, Result};
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Stdio;
+
+/// APIs the index sources are fetched from
+const ENDPOINTS: &[(&str, &str)] = &[("GitHub", "https://api.github.com"), ("NUR", NUR_REPOS_URL)];
+
+/// Outcome of a single check, with details on what was found or went wrong
+type Check = std::result::Result<String, String>;
+
+async fn check_endpoint(url: &str) -> Check {
+    let client = build_client().map_err(|err| err.to_string())?;
+    let response = client
+        .head(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| err.to_string())?;
+    Ok(format!("{} {}", url, response.status()))
+}
+
+async fn check_nix(nix: &NixCommand) -> Check {
+    let output = nix
+        .command(Some(""), &[OsStr::new("--version")])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|err| err.to_string())?;
+    if !output.status.success() {
+        return Err(format!(
+            "`--version` failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn check_writable(dir: &Path) -> Check {
+    let probe = dir.join(".flaker-doctor");
+    std::fs::write(&probe, b"")
+        .and_then(|()| std::fs::remove_file(&probe))
+        .map_err(|err| err.to_string())?;
+    Ok(dir.display().to_string())
+}
+
+/// Check that everything flaker needs is available, and print a pass/fail line per check
+pub async fn doctor(nix: Vec<NixCommand>, output_dir: &Path) -> Result<()> {
+    let mut checks: Vec<(String, Check)> = vec![];
+    for (name, url) in ENDPOINTS {
+        checks.push((format!("{} is reachable", name), check_endpoint(url).await));
+    }
+    for nix in &nix {
+        checks.push((format!("`{}` runs", nix), check_nix(nix).await));
+    }
+    checks.push((
+        "Output directory is writable".into(),
+        check_writable(output_dir),
+    ));
+
+    let mut failed = 0;
+    for (name, check) in &checks {
+        match check {
+            Ok(details) => println!("[ ok ] {}: {}", name, details),
+            Err(err) => {
+                println!("[FAIL] {}: {}", name, err);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, checks.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use crate::diffing::Labels;
+use crate::reporting::{load_report, MessageAnalysis};
+use arrow_array::{ArrayRef, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use color_eyre::eyre::{Context, Result};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// One message occurrence, flattened out of the nested report structure
+#[derive(Default)]
+struct Rows {
+    repo: Vec<String>,
+    category: Vec<&'static str>,
+    message: Vec<String>,
+    position: Vec<String>,
+    side: Vec<String>,
+}
+
+impl Rows {
+    fn add(&mut self, category: &'static str, log: MessageAnalysis, labels: &Labels) {
+        for (msg, repos) in log {
+            for (repo, diff) in repos {
+                for (side, positions) in labels.sides(diff) {
+                    for pos in positions {
+                        self.repo.push(repo.clone());
+                        self.category.push(category);
+                        self.message.push(msg.to_string());
+                        self.position.push(pos.to_string());
+                        self.side.push(side.to_string());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Write all message occurrences of the given diff results as `(repo, category, message, position, side)`
+/// rows into a Parquet file. Stdout diffs have no positions and are not part of the export.
+pub fn export_parquet(reports: Vec<PathBuf>, out: PathBuf) -> Result<()> {
+    let report = load_report(&reports)?;
+    let mut rows = Rows::default();
+    rows.add("err", report.err_log, &report.labels);
+    rows.add("warn", report.wrn_log, &report.labels);
+    rows.add("trace", report.trc_log, &report.labels);
+    rows.add("result", report.res_log, &report.labels);
+
+    let schema = Arc::new(Schema::new(
+        ["repo", "category", "message", "position", "side"]
+            .map(|name| Field::new(name, DataType::Utf8, false))
+            .to_vec(),
+    ));
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(rows.repo)),
+        Arc::new(StringArray::from(rows.category)),
+        Arc::new(StringArray::from(rows.message)),
+        Arc::new(StringArray::from(rows.position)),
+        Arc::new(StringArray::from(rows.side)),
+    ];
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+
+    let fh = File::create(&out)
+        .with_context(|| format!("Failed to open {} for writing.", out.display()))?;
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = ArrowWriter::try_new(fh, schema, Some(props))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    tracing::info!(rows = batch.num_rows(), out = %out.display(), "Exported report");
+    Ok(())
+}
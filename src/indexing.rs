@@ -2,7 +2,7 @@ use clap::{Parser, Subcommand};
 use color_eyre::eyre::{self, eyre, Context};
 use enumset::EnumSetType;
 use futures::future::err;
-use futures::{Stream, StreamExt, TryStreamExt};
+use futures::{FutureExt, Stream, StreamExt, TryFutureExt, TryStreamExt};
 use regex::Regex;
 use reqwest::IntoUrl;
 use serde::{Deserialize, Serialize};
@@ -11,21 +11,101 @@ use std::io::BufRead;
 use std::path::{Path, PathBuf};
 use std::process::{ExitStatus, Stdio};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use tracing::{warn, Instrument};
 use url::Url;
 
+use crate::progress::Progress;
+
+/// Header identifying all requests of one run, for correlating them with server logs
+const RUN_ID_HEADER: &str = "X-Request-Id";
+
+/// Settings shared by all clients, fixed once at startup
+#[derive(Debug)]
+struct ClientConfig {
+    user_agent: String,
+    run_id: String,
+}
+
+impl ClientConfig {
+    /// `user_agent` replaces the default `flaker v<version>`
+    fn new(user_agent: Option<String>) -> ClientConfig {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        ClientConfig {
+            user_agent: user_agent.unwrap_or_else(|| {
+                concat!(env!("CARGO_PKG_NAME"), " v", env!("CARGO_PKG_VERSION")).to_string()
+            }),
+            // Unique enough to tell runs apart in server logs
+            run_id: format!("{:x}-{:x}", now.as_nanos(), std::process::id()),
+        }
+    }
+}
+
+static CLIENT_CONFIG: std::sync::OnceLock<ClientConfig> = std::sync::OnceLock::new();
+
+/// Set up the clients of this run, before the first one is built
+pub fn configure_client(user_agent: Option<String>) {
+    let config = ClientConfig::new(user_agent);
+    tracing::debug!(
+        user_agent = config.user_agent,
+        run_id = config.run_id,
+        "Configured HTTP client"
+    );
+    if CLIENT_CONFIG.set(config).is_err() {
+        warn!("The HTTP client was already configured");
+    }
+}
+
 /// Helper method to build you a client.
-// TODO make injectable via a configuration mechanism
 pub fn build_client() -> color_eyre::Result<reqwest::Client, reqwest::Error> {
+    let config = CLIENT_CONFIG.get_or_init(|| ClientConfig::new(None));
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        RUN_ID_HEADER,
+        reqwest::header::HeaderValue::from_str(&config.run_id).expect("run ids are plain ASCII"),
+    );
     reqwest::Client::builder()
-        .user_agent(concat!(
-            env!("CARGO_PKG_NAME"),
-            " v",
-            env!("CARGO_PKG_VERSION")
-        ))
+        .user_agent(&config.user_agent)
+        .default_headers(headers)
         .build()
 }
 
+/// How often a request is tried before giving up
+const MAX_ATTEMPTS: u32 = 4;
+/// Delay before the first retry, doubled for every further one
+const INITIAL_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Whether a failed request might succeed when tried again
+fn is_transient(err: &reqwest::Error) -> bool {
+    err.is_timeout()
+        || err.is_connect()
+        || err.is_request()
+        || err.status().is_some_and(|status| status.is_server_error())
+}
+
+/// Run a request, retrying network errors and server errors with exponential backoff
+async fn with_retries<T, F, Fut>(url: &Url, mut request: F) -> Result<T, reqwest::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, reqwest::Error>>,
+{
+    let mut attempt = 1;
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match request().await {
+            Err(err) if attempt < MAX_ATTEMPTS && is_transient(&err) => {
+                tracing::warn!(%url, err = %err, attempt, ?backoff, "Request failed, retrying");
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+                backoff *= 2;
+            }
+            res => return res,
+        }
+    }
+}
+
 /// Helper method for doing various API calls
 #[tracing::instrument]
 async fn get_and_deserialize<T, U>(url: U) -> color_eyre::Result<T>
@@ -33,24 +113,200 @@ where
     T: for<'a> Deserialize<'a> + 'static,
     U: IntoUrl + std::fmt::Debug,
 {
-    let response = build_client()?
-        .get(url)
-        .send()
-        .await?
-        .error_for_status()?
-        .text()
-        .await?;
-    Ok(serde_json::from_str(&response)?)
+    /// How much of the response to show when it can't be deserialized
+    const BODY_PREVIEW_CHARS: usize = 500;
+
+    let url = url.into_url()?;
+    let client = build_client()?;
+    let response = with_retries(&url, || async {
+        client
+            .get(url.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await
+    })
+    .await?;
+    serde_json::from_str(&response).with_context(|| {
+        let preview: String = response.chars().take(BODY_PREVIEW_CHARS).collect();
+        let ellipsis = if preview.len() < response.len() {
+            "…"
+        } else {
+            ""
+        };
+        format!(
+            "Failed to deserialize response from {}, got:\n{}{}",
+            url, preview, ellipsis
+        )
+    })
+}
+
+/// How long fetching each repository took, to find the slow ones
+#[derive(Debug, Default)]
+struct Timings(std::sync::Mutex<Vec<(Url, Duration)>>);
+
+impl Timings {
+    fn record(&self, url: &Url, elapsed: Duration) {
+        self.0.lock().unwrap().push((url.clone(), elapsed));
+    }
+
+    /// Write `<seconds>\t<url>` lines to `out`, slowest first
+    fn write(self, out: &Path) -> color_eyre::Result<()> {
+        use std::io::Write;
+
+        let mut timings = self.0.into_inner().unwrap();
+        timings.sort_by_key(|t| std::cmp::Reverse(t.1));
+        let mut fh = std::io::BufWriter::new(
+            std::fs::File::create(out)
+                .with_context(|| format!("Failed to open {} for writing.", out.display()))?,
+        );
+        for (url, elapsed) in timings {
+            writeln!(fh, "{:.3}\t{}", elapsed.as_secs_f64(), url)?;
+        }
+        fh.flush()?;
+        Ok(())
+    }
+}
+
+/// A repository to pin, as listed by NUR and in failures files
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Repo {
+    url: Url,
+    branch: Option<String>,
+    #[serde(default)]
+    submodules: bool,
+}
+
+/// A repository that could not be pinned, as written to `--failures-file`
+#[derive(Debug, Serialize, Deserialize)]
+struct Failure {
+    #[serde(flatten)]
+    repo: Repo,
+    // Optional, so that hand-written lists of repositories can be retried too
+    #[serde(default)]
+    error: String,
+}
+
+/// Repositories that could not be pinned, to fetch only them again with `--retry-failures`
+#[derive(Debug, Default)]
+struct Failures(std::sync::Mutex<Vec<Failure>>);
+
+impl Failures {
+    fn record(&self, repo: Repo, error: String) {
+        self.0.lock().unwrap().push(Failure { repo, error });
+    }
+
+    /// Write the failures to `out` as a JSON list, ordered by URL
+    fn write(self, out: &Path) -> color_eyre::Result<()> {
+        let mut failures = self.0.into_inner().unwrap();
+        failures.sort_by(|a, b| a.repo.url.cmp(&b.repo.url));
+        let fh = std::fs::File::create(out)
+            .with_context(|| format!("Failed to open {} for writing.", out.display()))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(fh), &failures)?;
+        Ok(())
+    }
+
+    /// The repositories of a file written by [`Failures::write`]
+    fn read(path: &Path) -> color_eyre::Result<Vec<Repo>> {
+        let fh = std::fs::File::open(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let failures: Vec<Failure> = serde_json::from_reader(std::io::BufReader::new(fh))
+            .with_context(|| format!("Failed to parse the failures file {}", path.display()))?;
+        Ok(failures.into_iter().map(|failure| failure.repo).collect())
+    }
 }
 
+/// Limit on the git processes running at once over all source sets, with `--git-jobs`
+static GIT_JOBS: std::sync::OnceLock<tokio::sync::Semaphore> = std::sync::OnceLock::new();
+
+/// Fetch the pin of the repository at `url`, recording how long that took in `timings`
 #[tracing::instrument(fields(url = %url), skip_all)]
 async fn fetch_pin(
     url: &Url,
     branch: Option<String>,
     submodules: bool,
+    timings: &Timings,
+) -> anyhow::Result<npins::Pin> {
+    // Waiting for a turn doesn't count as fetching
+    let _job = match GIT_JOBS.get() {
+        Some(jobs) => Some(
+            jobs.acquire()
+                .await
+                .expect("The git job limit is never closed"),
+        ),
+        None => None,
+    };
+    let start = Instant::now();
+    let pin = fetch_pin_untimed(url, branch, submodules).await;
+    let elapsed = start.elapsed();
+    tracing::debug!(?elapsed, ok = pin.is_ok(), "Fetched pin");
+    timings.record(url, elapsed);
+    pin
+}
+
+/// How many default branches are remembered, the least recently used ones are forgotten first
+const DEFAULT_BRANCH_CACHE_SIZE: usize = 4096;
+
+/// Default branches by repository, with when they were last used
+#[derive(Default)]
+struct BranchCache {
+    clock: u64,
+    branches: HashMap<String, (String, u64)>,
+}
+
+impl BranchCache {
+    fn get(&mut self, key: &str) -> Option<String> {
+        self.clock += 1;
+        let (branch, used) = self.branches.get_mut(key)?;
+        *used = self.clock;
+        Some(branch.clone())
+    }
+
+    fn insert(&mut self, key: String, branch: String) {
+        if self.branches.len() >= DEFAULT_BRANCH_CACHE_SIZE {
+            let oldest = self
+                .branches
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.branches.remove(&oldest);
+            }
+        }
+        self.branches.insert(key, (branch, self.clock));
+    }
+}
+
+static DEFAULT_BRANCHES: std::sync::LazyLock<std::sync::Mutex<BranchCache>> =
+    std::sync::LazyLock::new(Default::default);
+
+/// The same repository may be spelled differently by different sources.
+/// [`Url`] already lowercases the host, paths may be case sensitive
+fn repo_key(url: &Url) -> String {
+    let key = url.as_str().trim_end_matches('/');
+    key.strip_suffix(".git").unwrap_or(key).to_string()
+}
+
+/// Look up the default branch of `url`, only going to the network once per repository
+async fn default_branch(url: &Url) -> anyhow::Result<String> {
+    let key = repo_key(url);
+    if let Some(branch) = DEFAULT_BRANCHES.lock().unwrap().get(&key) {
+        tracing::debug!(%url, branch, "Default branch is cached");
+        return Ok(branch);
+    }
+    let branch = npins::git::fetch_default_branch(url).await?;
+    DEFAULT_BRANCHES.lock().unwrap().insert(key, branch.clone());
+    Ok(branch)
+}
+
+async fn fetch_pin_untimed(
+    url: &Url,
+    branch: Option<String>,
+    submodules: bool,
 ) -> anyhow::Result<npins::Pin> {
     // Always fetch default branch as a small first sanity check for the repo
-    let default_branch = npins::git::fetch_default_branch(url).await?;
+    let default_branch = default_branch(url).await?;
     let mut pin: npins::Pin = npins::git::GitPin::git(
         url.clone(),
         branch.clone().unwrap_or(default_branch),
@@ -62,6 +318,11 @@ async fn fetch_pin(
     Ok(pin)
 }
 
+/// All repositories known to NUR
+/// <https://github.com/nix-community/NUR/blob/main/repos.json>
+pub const NUR_REPOS_URL: &str =
+    "https://raw.githubusercontent.com/nix-community/NUR/refs/heads/main/repos.json";
+
 #[derive(EnumSetType, Debug)]
 pub enum SourceSet {
     /// The Nixpkgs repo
@@ -73,89 +334,672 @@ pub enum SourceSet {
     Github,
 }
 
+impl SourceSet {
+    /// Name on the command line
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SourceSet::Nixpkgs => "nixpkgs",
+            SourceSet::Nur => "nur",
+            SourceSet::Github => "github",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            SourceSet::Nixpkgs => "The Nixpkgs repo",
+            SourceSet::Nur => "All NUR repositories",
+            SourceSet::Github => "All GitHub repositories with a flake.lock (not implemented yet)",
+        }
+    }
+
+    /// Check that fetching this source set can work at all, i.e. that its server is reachable
+    async fn check_prerequisites(self) -> color_eyre::Result<()> {
+        let url = match self {
+            SourceSet::Nixpkgs => "https://github.com/NixOS/Nixpkgs",
+            SourceSet::Nur => NUR_REPOS_URL,
+            // Doesn't fetch anything yet
+            SourceSet::Github => return Ok(()),
+        };
+        build_client()?
+            .head(url)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .wrap_err_with(|| format!("{} is not reachable", url))?;
+        Ok(())
+    }
+}
+
 impl FromStr for SourceSet {
+    type Err = ();
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        enumset::EnumSet::<SourceSet>::all()
+            .into_iter()
+            .find(|source| source.as_str() == s)
+            .ok_or(())
+    }
+}
+
+/// How the index gets written
+#[derive(Debug, Clone, Copy)]
+pub enum IndexFormat {
+    /// A regular npins `sources.json`, written once all pins are fetched
+    Pins,
+    /// One [`IndexEntry`] per line, written as soon as each pin is fetched
+    Ndjson,
+}
+
+impl FromStr for IndexFormat {
     type Err = ();
     fn from_str(s: &str) -> std::result::Result<Self, ()> {
         match s {
-            "nixpkgs" => Ok(SourceSet::Nixpkgs),
-            "nur" => Ok(SourceSet::Nur),
-            "github" => Ok(SourceSet::Github),
+            "pins" => Ok(IndexFormat::Pins),
+            "ndjson" => Ok(IndexFormat::Ndjson),
             _ => Err(()),
         }
     }
 }
 
+/// A single line of an NDJSON index
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub name: String,
+    pub pin: npins::Pin,
+}
+
+/// How many fetched pins may wait for the writer before fetching stalls
+const PIN_CHANNEL_SIZE: usize = 64;
+
+type PinSender = tokio::sync::mpsc::Sender<(String, npins::Pin)>;
+
+/// Options of `build-index`, from the command line or a `--config` file
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IndexOptions {
+    /// Which source sets to include.
+    /// Comma separated list. Available source sets: `nixpkgs`, `nur`, `github`
+    #[arg(long, value_delimiter = ',', default_value = "*")]
+    pub sources: Vec<String>,
+    /// Output format: `pins` for an npins `sources.json`, or `ndjson` to write
+    /// one pin per line as soon as it is fetched. NDJSON lines are in the order the fetches
+    /// finish, and a repository pinned twice gets two lines
+    #[arg(long, default_value = "pins")]
+    pub format: String,
+    /// Stop after this many pins have been fetched, over all source sets
+    #[arg(long)]
+    pub limit: Option<usize>,
+    /// Fetch all source sets at the same time instead of one after another.
+    /// The progress file then only shows the most recently started source set
+    #[arg(long)]
+    pub parallel_sources: bool,
+    /// How many repositories of a source set are fetched at the same time
+    #[arg(long, default_value_t = 20)]
+    pub concurrency: usize,
+    /// How many repositories are fetched with git at the same time, over all source sets.
+    /// Each of them runs git processes, so lower this on machines with little network or disk
+    /// bandwidth. Only `--concurrency` limits them by default
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub git_jobs: Option<u32>,
+    /// Branch of Nixpkgs to pin
+    #[arg(long, default_value = "release-24.05")]
+    pub nixpkgs_branch: String,
+    /// Skip the NUR repos whose configured branch matches this regex, e.g. `^(master|main)$`
+    /// for the ones that change all the time
+    #[arg(long)]
+    pub nur_branch_filter: Option<String>,
+    /// Skip the NUR repos whose branch does *not* match `--nur-branch-filter` instead
+    #[arg(long, requires = "nur_branch_filter")]
+    pub nur_invert_branch_filter: bool,
+    /// Skip the NUR repos that don't configure a branch and follow their default branch
+    #[arg(long)]
+    pub nur_skip_default_branch: bool,
+    /// Don't download Git LFS objects of the fetched repositories, only their pointer files.
+    /// Nix files are hardly ever stored in LFS, so this only saves bandwidth
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_name = "BOOL")]
+    pub skip_lfs: bool,
+    /// Check up front that each source set can be fetched, and skip the ones that can't
+    /// with a warning instead of failing the whole build
+    #[arg(long)]
+    pub skip_sources_missing_prereqs: bool,
+    /// Write how long fetching each repository took to this file,
+    /// as `<seconds>\t<url>` lines with the slowest first
+    #[arg(long)]
+    pub timing_log: Option<PathBuf>,
+    /// Periodically write the progress as JSON to this file
+    #[arg(long)]
+    pub progress_file: Option<PathBuf>,
+    /// Print which repositories were added, removed or moved to another revision compared
+    /// to this earlier index, in either format. May be the output file itself
+    #[arg(long)]
+    pub since_index: Option<PathBuf>,
+    /// Write the repositories that could not be fetched to this file, as a JSON list of
+    /// `{ "url", "branch", "submodules", "error" }` objects
+    #[arg(long)]
+    pub failures_file: Option<PathBuf>,
+    /// Only fetch the repositories of a `--failures-file` from an earlier run instead of the
+    /// source sets, and merge them into the existing index at the output path
+    #[arg(long, value_name = "PATH")]
+    pub retry_failures: Option<PathBuf>,
+    /// Record how the index was built, with the full command line, under a `provenance` key.
+    /// Only for `--format pins`; npins ignores the key, but drops it when rewriting the file
+    #[arg(long)]
+    pub embed_provenance: bool,
+}
+
+impl IndexOptions {
+    fn source_sets(&self) -> color_eyre::Result<enumset::EnumSet<SourceSet>> {
+        if self.sources.iter().any(|source| source.contains('*')) {
+            return Ok(enumset::EnumSet::all());
+        }
+        self.sources
+            .iter()
+            .map(|source| {
+                SourceSet::from_str(source).map_err(|()| eyre!("Invalid source set '{}'", source))
+            })
+            .collect()
+    }
+
+    /// Check the options that are only looked at once the build is underway
+    pub fn validate(&self) -> color_eyre::Result<()> {
+        self.source_sets()?;
+        IndexFormat::from_str(self.format.as_str())
+            .map_err(|()| eyre!("Invalid index format '{}'", self.format))?;
+        if let Some(filter) = &self.nur_branch_filter {
+            Regex::new(filter).wrap_err("Invalid --nur-branch-filter")?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of repositories in a list of the `--failures-file` format
+pub fn count_repos(path: &Path) -> color_eyre::Result<usize> {
+    Failures::read(path).map(|repos| repos.len())
+}
+
 pub async fn build_index(
-    sources: enumset::EnumSet<SourceSet>,
     out: PathBuf,
+    options: &IndexOptions,
+    progress: &Progress,
 ) -> color_eyre::Result<()> {
-    let mut pins = npins::NixPins::default();
+    let sources = options.source_sets()?;
+    let sources = if options.skip_sources_missing_prereqs {
+        skip_unavailable_sources(sources).await
+    } else {
+        sources
+    };
+    let format = IndexFormat::from_str(options.format.as_str())
+        .map_err(|()| eyre!("Invalid index format '{}'", options.format))?;
+    if options.embed_provenance && matches!(format, IndexFormat::Ndjson) {
+        return Err(eyre!(
+            "`--embed-provenance` only works with `--format pins`"
+        ));
+    }
+    if options.since_index.is_some() && crate::progress::is_stdout(&out) {
+        return Err(eyre!(
+            "`--since-index` can't compare against an index written to stdout"
+        ));
+    }
+    // Read up front, as it may be overwritten and to not find out it's broken only at the end
+    let old_index = options.since_index.as_deref().map(load_index).transpose()?;
+    let retry = options
+        .retry_failures
+        .as_deref()
+        .map(Failures::read)
+        .transpose()?;
+    // Retried pins are merged into the index, so keep the ones fetched before
+    let base = match &retry {
+        Some(_) if !crate::progress::is_stdout(&out) && out.exists() => {
+            read_index(&out)?.collect::<color_eyre::Result<Vec<_>>>()?
+        }
+        _ => Vec::new(),
+    };
+    if let Some(jobs) = options.git_jobs {
+        if GIT_JOBS
+            .set(tokio::sync::Semaphore::new(jobs as usize))
+            .is_err()
+        {
+            warn!("The git job limit was already set");
+        }
+    }
+    let (pins_tx, pins_rx) = tokio::sync::mpsc::channel(PIN_CHANNEL_SIZE);
+    let writer = tokio::spawn(
+        write_index(pins_rx, out.clone(), format, base, options.embed_provenance)
+            .instrument(tracing::info_span!("Writing pins", out_path = ?out.display())),
+    );
+
+    let timings = Timings::default();
+    let failures = Failures::default();
+    let fetched = match retry {
+        Some(repos) => {
+            fetch_repos_again(repos, &pins_tx, options, &timings, &failures, progress).await
+        }
+        None => fetch_sources(sources, &pins_tx, options, &timings, &failures, progress).await,
+    };
+    drop(pins_tx);
+    // Also useful after a failure, to see what took so long
+    if let Some(timing_log) = &options.timing_log {
+        timings.write(timing_log)?;
+    }
+    if let Some(failures_file) = &options.failures_file {
+        failures.write(failures_file)?;
+    }
+    if fetched.is_err() {
+        // Don't leave a `sources.json` behind that looks complete
+        writer.abort();
+    }
+    // A failing writer also makes the fetchers fail, so its error is the more useful one
+    match writer.await {
+        Ok(written) => written?,
+        Err(err) if err.is_cancelled() => {}
+        Err(err) => return Err(err.into()),
+    }
+    let panicked = fetched?;
+    if !panicked.is_empty() {
+        return Err(eyre!(
+            "Fetching {} panicked, the index only contains the pins of the other source sets",
+            panicked
+                .iter()
+                .map(|source| source.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    if let Some(old_index) = old_index {
+        print_index_changes(&old_index, &load_index(&out)?);
+    }
+    Ok(())
+}
+
+type IndexPins = Box<dyn Iterator<Item = color_eyre::Result<(String, serde_json::Value)>>>;
+
+/// Read the pins of an index of either [`IndexFormat`], as JSON since they are opaque to us.
+/// NDJSON indices are streamed one line at a time, so they never have to fit in memory at once
+fn read_index(path: &Path) -> color_eyre::Result<IndexPins> {
+    #[derive(Deserialize)]
+    struct Entry {
+        name: String,
+        pin: serde_json::Value,
+    }
+    #[derive(Deserialize)]
+    struct Pins {
+        pins: HashMap<String, serde_json::Value>,
+    }
+
+    let open = || {
+        std::fs::File::open(path)
+            .map(std::io::BufReader::new)
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))
+    };
+    let mut reader = open()?;
+    // Only NDJSON has a complete entry on its first line
+    let mut first = String::new();
+    if reader.read_line(&mut first)? == 0 {
+        // An NDJSON index without any pins
+        return Ok(Box::new(std::iter::empty()));
+    }
+    let Ok(Entry { name, pin }) = serde_json::from_str(&first) else {
+        let Pins { pins } = serde_json::from_reader(open()?)
+            .wrap_err_with(|| format!("Failed to parse the index {}", path.display()))?;
+        return Ok(Box::new(pins.into_iter().map(Ok)));
+    };
+    let path = path.to_owned();
+    let rest = reader
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(move |(i, line)| {
+            let Entry { name, pin } = serde_json::from_str(&line?).wrap_err_with(|| {
+                format!("Failed to parse line {} of {}", i + 2, path.display())
+            })?;
+            Ok((name, pin))
+        });
+    Ok(Box::new(std::iter::once(Ok((name, pin))).chain(rest)))
+}
 
-    tracing::info!(sources = ?sources, "Scraping sources");
+/// The revision of each pin of an index, or the whole pin for pin types without one
+fn load_index(path: &Path) -> color_eyre::Result<HashMap<String, String>> {
+    read_index(path)?
+        .map(|pin| {
+            let (name, pin) = pin?;
+            let revision = match pin.get("revision") {
+                Some(serde_json::Value::String(revision)) => revision.clone(),
+                _ => pin.to_string(),
+            };
+            Ok((name, revision))
+        })
+        .collect()
+}
+
+/// Print a changelog of the repositories between two indices, sorted by name
+fn print_index_changes(old: &HashMap<String, String>, new: &HashMap<String, String>) {
+    let names: std::collections::BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    let (mut added, mut removed, mut updated) = (0, 0, 0);
+    for name in names {
+        match (old.get(name), new.get(name)) {
+            (None, Some(_)) => {
+                added += 1;
+                println!("added: {}", name);
+            }
+            (Some(_), None) => {
+                removed += 1;
+                println!("removed: {}", name);
+            }
+            (Some(old), Some(new)) if old != new => {
+                updated += 1;
+                println!("updated: {}: {} → {}", name, old, new);
+            }
+            _ => {}
+        }
+    }
+    tracing::info!(added, removed, updated, "Compared to the earlier index");
+}
+
+/// Leave out the source sets whose prerequisites are missing
+async fn skip_unavailable_sources(
+    sources: enumset::EnumSet<SourceSet>,
+) -> enumset::EnumSet<SourceSet> {
+    let mut available = enumset::EnumSet::empty();
     for source in sources {
-        match source {
-            SourceSet::Nixpkgs => {
-                let NIXPKGS_URL = Url::parse("https://github.com/NixOS/Nixpkgs").unwrap();
-                pins.pins.insert(
-                    NIXPKGS_URL.to_string(),
-                    fetch_pin(&NIXPKGS_URL, Some("release-24.05".into()), false)
-                        .await
-                        .map_err(|err| {
-                            eyre!(Box::<dyn std::error::Error + Send + Sync + 'static>::from(
-                                err
-                            ))
-                        })?,
-                );
+        match source.check_prerequisites().await {
+            Ok(()) => available |= source,
+            Err(err) => tracing::warn!(
+                source = source.as_str(),
+                err = format!("{:#}", err),
+                "Skipping source set, its prerequisites are missing"
+            ),
+        }
+    }
+    if available != sources {
+        tracing::info!(
+            skipped = ?(sources - available),
+            "Building the index without some source sets"
+        );
+    }
+    available
+}
+
+/// The message a panic was raised with
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|msg| msg.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".into())
+}
+
+type PinStream<'a> = futures::stream::LocalBoxStream<'a, color_eyre::Result<(String, npins::Pin)>>;
+
+/// Lazily fetch the pins of one source set
+fn source_pins<'a>(
+    source: SourceSet,
+    options: &'a IndexOptions,
+    timings: &'a Timings,
+    failures: &'a Failures,
+    progress: &'a Progress,
+) -> PinStream<'a> {
+    match source {
+        SourceSet::Nixpkgs => futures::stream::once(async move {
+            progress.stage("nixpkgs", Some(1));
+            let NIXPKGS_URL = Url::parse("https://github.com/NixOS/Nixpkgs").unwrap();
+            let pin = fetch_pin(
+                &NIXPKGS_URL,
+                Some(options.nixpkgs_branch.clone()),
+                false,
+                timings,
+            )
+            .await
+            .map_err(|err| {
+                eyre!(Box::<dyn std::error::Error + Send + Sync + 'static>::from(
+                    err
+                ))
+            })?;
+            progress.inc();
+            Ok((NIXPKGS_URL.to_string(), pin))
+        })
+        .boxed_local(),
+        SourceSet::Nur => {
+            #[derive(Debug, Deserialize)]
+            struct Repos {
+                repos: HashMap<String, Repo>,
             }
-            SourceSet::Nur => {
-                #[derive(Debug, Deserialize)]
-                struct Repo {
-                    url: url::Url,
-                    branch: Option<String>,
-                    #[serde(default)]
-                    submodules: bool,
+            async move {
+                let branch_filter = options
+                    .nur_branch_filter
+                    .as_deref()
+                    .map(Regex::new)
+                    .transpose()
+                    .wrap_err("Invalid --nur-branch-filter")?;
+                let Repos { mut repos } = get_and_deserialize(NUR_REPOS_URL).await?;
+                let total = repos.len();
+                repos.retain(|_, repo| match (&repo.branch, &branch_filter) {
+                    (None, _) => !options.nur_skip_default_branch,
+                    (Some(branch), Some(filter)) => {
+                        filter.is_match(branch) == options.nur_invert_branch_filter
+                    }
+                    (Some(_), None) => true,
+                });
+                if repos.len() < total {
+                    tracing::info!(
+                        skipped = total - repos.len(),
+                        "Skipped NUR repos by their branch"
+                    );
                 }
-                #[derive(Debug, Deserialize)]
-                struct Repos {
-                    repos: HashMap<String, Repo>,
+                progress.stage("nur", Some(repos.len() as u64));
+                let stream =
+                    fetch_repos(repos.into_values(), options, timings, failures, progress).map(Ok);
+                color_eyre::Result::<_, eyre::Report>::Ok(stream)
+            }
+            .instrument(tracing::info_span!("Scraping NUR"))
+            .try_flatten_stream()
+            .boxed_local()
+        }
+        SourceSet::Github => futures::stream::empty().boxed_local(),
+    }
+}
+
+/// Fetch the pins of many repositories at once, skipping and recording the ones that fail
+fn fetch_repos<'a>(
+    repos: impl IntoIterator<Item = Repo> + 'a,
+    options: &'a IndexOptions,
+    timings: &'a Timings,
+    failures: &'a Failures,
+    progress: &'a Progress,
+) -> impl Stream<Item = (String, npins::Pin)> + 'a {
+    futures::stream::iter(repos)
+        .map(move |repo| async move {
+            let Repo {
+                url,
+                branch,
+                submodules,
+            } = repo.clone();
+            let fetch = fetch_pin(&url, branch, submodules, timings);
+            // A bug triggered by one repo shouldn't lose all the others
+            let res = match std::panic::AssertUnwindSafe(fetch).catch_unwind().await {
+                Ok(Ok(pin)) => Some((url.to_string(), pin)),
+                Ok(Err(err)) => {
+                    tracing::warn!(err = ?err, %url, "Failed to fetch pin, ignoring");
+                    failures.record(repo, format!("{:#}", err));
+                    None
                 }
-                async {
-                    // <https://github.com/nix-community/NUR/blob/main/repos.json>
-                    let Repos { repos } = get_and_deserialize("https://raw.githubusercontent.com/nix-community/NUR/refs/heads/main/repos.json").await?;
-                    let stream = futures::stream::iter(repos)
-                        .map(|(_, Repo { url, branch, submodules })| async move {
-                            match fetch_pin(&url, branch, submodules).await {
-                                Ok(pin) => Some((url.to_string(), pin)),
-                                Err(err) => {
-                                    tracing::warn!(err = ?err, %url, "Failed to fetch pin, ignoring");
-                                    None
-                                }
-                            }
-                        })
-                        .buffer_unordered(20)
-                        .filter_map(|val| async {val});
-                    futures::pin_mut!(stream);
-                    while let Some((k, v)) = stream.next().await {
-                        pins.pins.insert(k, v);
-                    }
-                    color_eyre::Result::<(), eyre::Report>::Ok(())
-                }.instrument(tracing::info_span!("Scraping NUR")).await?;
+                Err(panic) => {
+                    let panic = panic_message(&*panic);
+                    tracing::error!(panic, %url, "Fetching pin panicked, ignoring");
+                    failures.record(repo, panic);
+                    None
+                }
+            };
+            progress.inc();
+            res
+        })
+        .buffer_unordered(options.concurrency)
+        .filter_map(|val| async { val })
+}
+
+/// Fetch only the repositories that failed in an earlier run, for `--retry-failures`
+async fn fetch_repos_again(
+    repos: Vec<Repo>,
+    pins_tx: &PinSender,
+    options: &IndexOptions,
+    timings: &Timings,
+    failures: &Failures,
+    progress: &Progress,
+) -> color_eyre::Result<Vec<SourceSet>> {
+    tracing::info!(repos = repos.len(), "Retrying failed repositories");
+    progress.stage("retry", Some(repos.len() as u64));
+    let pins = fetch_repos(repos, options, timings, failures, progress);
+    futures::pin_mut!(pins);
+    while let Some(pin) = pins.next().await {
+        pins_tx
+            .send(pin)
+            .await
+            .map_err(|_| eyre!("The index writer stopped early"))?;
+    }
+    Ok(Vec::new())
+}
+
+/// Fetch the pins of all `sources`, stopping early once `limit` pins have been fetched.
+/// Returns the source sets that were cut short by a panic
+async fn fetch_sources(
+    sources: enumset::EnumSet<SourceSet>,
+    pins_tx: &PinSender,
+    options: &IndexOptions,
+    timings: &Timings,
+    failures: &Failures,
+    progress: &Progress,
+) -> color_eyre::Result<Vec<SourceSet>> {
+    let (limit, parallel) = (options.limit, options.parallel_sources);
+    tracing::info!(sources = ?sources, parallel, "Scraping sources");
+    // A panic ends the stream of its source set, with the panic as its last item
+    let streams = sources.iter().map(|source| {
+        std::panic::AssertUnwindSafe(source_pins(source, options, timings, failures, progress))
+            .catch_unwind()
+            .map(move |pin| pin.map_err(|panic| (source, panic_message(&*panic))))
+            .boxed_local()
+    });
+    let mut pins = if parallel {
+        futures::stream::select_all(streams).boxed_local()
+    } else {
+        futures::stream::iter(streams).flatten().boxed_local()
+    };
+
+    let mut fetched = 0;
+    let mut panicked = vec![];
+    while let Some(pin) = pins.next().await {
+        let pin = match pin {
+            Ok(pin) => pin?,
+            Err((source, panic)) => {
+                tracing::error!(
+                    source = source.as_str(),
+                    panic,
+                    "Fetching source set panicked, keeping the pins of the others"
+                );
+                panicked.push(source);
+                continue;
             }
-            SourceSet::Github => {}
+        };
+        pins_tx
+            .send(pin)
+            .await
+            .map_err(|_| eyre!("The index writer stopped early"))?;
+        fetched += 1;
+        // Dropping the stream cancels the fetches still in flight
+        if limit.is_some_and(|limit| fetched >= limit) {
+            tracing::info!(?limit, "Reached the limit of pins");
+            break;
         }
     }
+    Ok(panicked)
+}
 
-    async {
-        let out = &out;
-        let mut fh = std::fs::File::create(out)
-            .with_context(|| format!("Failed to open {} for writing.", out.display()))?;
-        serde_json::to_writer_pretty(&mut fh, &pins.to_value_versioned())?;
-        use std::io::Write;
+/// Drain fetched pins into `out` until all senders are gone, keeping the pins of `base` that
+/// weren't fetched again
+async fn write_index(
+    mut pins_rx: tokio::sync::mpsc::Receiver<(String, npins::Pin)>,
+    out: PathBuf,
+    format: IndexFormat,
+    mut base: Vec<(String, serde_json::Value)>,
+    embed_provenance: bool,
+) -> color_eyre::Result<()> {
+    use std::io::Write;
+
+    // A bare file name has an empty parent, which is the current directory
+    if let Some(parent) = out.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    // The whole index is only known at the end, so write it next to `out` and move it over
+    // the old one then. That way a failed run never leaves a truncated `sources.json` behind
+    let tmp = match format {
+        _ if crate::progress::is_stdout(&out) => None,
+        IndexFormat::Pins => Some(crate::progress::temp_file_next_to(&out).with_context(|| {
+            format!(
+                "Failed to create a temporary file next to {}",
+                out.display()
+            )
+        })?),
+        IndexFormat::Ndjson => None,
+    };
+    let mut fh: std::io::BufWriter<Box<dyn Write + Send>> = std::io::BufWriter::new(match &tmp {
+        Some(tmp) => Box::new(tmp.reopen()?),
+        None if crate::progress::is_stdout(&out) => Box::new(std::io::stdout()),
+        None => Box::new(
+            std::fs::File::create(&out)
+                .with_context(|| format!("Failed to open {} for writing.", out.display()))?,
+        ),
+    });
+    if let IndexFormat::Ndjson = format {
+        // Readers take the last line of a name, so pins fetched again replace these
+        for (name, pin) in std::mem::take(&mut base) {
+            serde_json::to_writer(&mut fh, &serde_json::json!({ "name": name, "pin": pin }))?;
+            fh.write_all(b"\n")?;
+        }
+    }
+    let mut pins = npins::NixPins::default();
+    while let Some((name, pin)) = pins_rx.recv().await {
+        match format {
+            IndexFormat::Pins => {
+                // The same repository may be listed twice, e.g. with different branches. Don't
+                // let the fetch that happens to finish last win, keep the pin that sorts first
+                let keep_old = match pins.pins.get(&name) {
+                    Some(old) => serde_json::to_string(old)? <= serde_json::to_string(&pin)?,
+                    None => false,
+                };
+                if keep_old {
+                    tracing::debug!(name, "Pinned twice, keeping the pin that sorts first");
+                } else {
+                    pins.pins.insert(name, pin);
+                }
+            }
+            IndexFormat::Ndjson => {
+                serde_json::to_writer(&mut fh, &IndexEntry { name, pin })?;
+                fh.write_all(b"\n")?;
+                // Keep the file usable by readers following along
+                fh.flush()?;
+            }
+        }
+    }
+    if let IndexFormat::Pins = format {
+        let mut value = pins.to_value_versioned();
+        if let Some(merged) = value.get_mut("pins").and_then(|pins| pins.as_object_mut()) {
+            for (name, pin) in base {
+                merged.entry(name).or_insert(pin);
+            }
+        }
+        if let (true, Some(value)) = (embed_provenance, value.as_object_mut()) {
+            value.insert(
+                "provenance".to_string(),
+                serde_json::to_value(crate::provenance::Provenance::current())?,
+            );
+        }
+        // Keep re-generated indices diffable, whichever map type serde_json was built with
+        value.sort_all_objects();
+        serde_json::to_writer_pretty(&mut fh, &value)?;
         fh.write_all(b"\n")?;
-        color_eyre::Result::<(), eyre::Report>::Ok(())
     }
-    .instrument(tracing::info_span!("Writing pins", out_path = ?out.display()))
-    .await?;
-
+    fh.flush()?;
+    if let Some(tmp) = tmp {
+        tmp.persist(&out)
+            .with_context(|| format!("Failed to move the index to {}", out.display()))?;
+    }
     Ok(())
 }
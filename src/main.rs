@@ -1,15 +1,21 @@
 mod diffing;
+mod doctor;
+#[cfg(feature = "parquet")]
+mod export;
 mod indexing;
+mod progress;
+mod provenance;
 mod reporting;
+#[cfg(feature = "schema")]
+mod schema;
 
-use crate::reporting::{report, ReportVerbosity};
-use clap::{Parser, Subcommand};
+use crate::reporting::report;
+use clap::{FromArgMatches, Parser, Subcommand};
 use color_eyre::eyre::{eyre, Context, Result};
 use enumset::EnumSetType;
 use futures::Stream;
-use std::fs::File;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use tracing::Instrument;
 
@@ -23,52 +29,460 @@ use tracing::Instrument;
     disable_colored_help = false,
     color = clap::ColorChoice::Auto
 )]
+struct Args {
+    /// Log level for flaker's own output. Dependencies only log warnings and errors.
+    /// Setting `RUST_LOG` overrides this completely
+    #[arg(long, global = true, default_value = "debug")]
+    min_level: tracing::Level,
+    #[command(flatten)]
+    clobber: ClobberOptions,
+    /// User agent for all HTTP requests, to tell flaker instances apart.
+    /// Each run also sends an `X-Request-Id` header to correlate its requests with server logs
+    #[arg(long, global = true)]
+    user_agent: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
 enum Command {
     /// Build an index of repositories based on source sets
     BuildIndex {
-        /// Which source sets to include.
-        /// Comma separated list. Available source sets: `nixpkgs`, `nur`, `github`
-        #[arg(long, default_value = "*")]
-        sources: String,
+        /// Path to write the index to, or `-` for stdout
         #[arg()]
         out: PathBuf,
+        /// JSON or TOML file with defaults for the options below, by their names in snake_case.
+        /// Options given on the command line take precedence
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[command(flatten)]
+        options: indexing::IndexOptions,
     },
+    /// List the source sets available to `build-index`
+    Sources,
     /// Run two Nix versions on all sources and diff the results
     NixParse {
-        /// Path to the output file, will be overridden if present
+        /// Path to the output file, or `-` for stdout
         #[arg(long, short, default_value = "report.json")]
         output_file: PathBuf,
-        /// Path to the folder to diff
+        /// Path to the folder to diff, or to a `.tar`, `.tar.gz` or `.tgz` archive
+        #[arg()]
+        folder: PathBuf,
+        /// Path to a Nix binary, `ssh://host:/path/to/nix-instantiate` for a remote one,
+        /// or `cmd:<command line>` to run a command like `nix run nixpkgs#nix -- nix-instantiate`
+        #[arg()]
+        nix_a: String,
+        /// Path to a Nix binary, `ssh://host:/path/to/nix-instantiate` for a remote one,
+        /// or `cmd:<command line>` to run a command like `nix run nixpkgs#nix -- nix-instantiate`.
+        /// Not given with `--corpus`
+        #[arg(required_unless_present = "corpus", conflicts_with = "corpus")]
+        nix_b: Option<String>,
+        /// Use the outputs recorded by `record-corpus` as the first Nix,
+        /// and `NIX_A` as the second one
+        #[arg(long)]
+        corpus: Option<PathBuf>,
+        #[command(flatten)]
+        options: diffing::DiffOptions,
+        /// Periodically write the progress as JSON to this file
+        #[arg(long)]
+        progress_file: Option<PathBuf>,
+        /// Record how the result was produced, with the command line, under a `provenance` key
+        #[arg(long)]
+        embed_provenance: bool,
+        #[command(flatten)]
+        post: PostOptions,
+    },
+    /// Run an ordered list of Nix versions on all sources, and find for every file between
+    /// which two adjacent versions its output first changed
+    NixBisect {
+        /// Path to the output file, or `-` for stdout
+        #[arg(long, short, default_value = "bisect.json")]
+        output_file: PathBuf,
+        /// Path to the folder to bisect
+        #[arg()]
+        folder: PathBuf,
+        /// Paths to Nix binaries, `ssh://host:/path/to/nix-instantiate` for remote ones,
+        /// or `cmd:<command line>` for commands,
+        /// from the oldest to the newest version
+        #[arg(num_args = 2..)]
+        nix: Vec<String>,
+        #[command(flatten)]
+        options: diffing::DiffOptions,
+        /// Periodically write the progress as JSON to this file
+        #[arg(long)]
+        progress_file: Option<PathBuf>,
+    },
+    /// Run one Nix version on all sources and record its outputs, for `nix-parse --corpus`.
+    /// Options that only affect comparing have no effect
+    RecordCorpus {
+        /// Path to the corpus file, or `-` for stdout
+        #[arg(long, short, default_value = "corpus.json")]
+        output_file: PathBuf,
+        /// Path to the folder to record
         #[arg()]
         folder: PathBuf,
-        /// Path to a Nix binary
+        /// Path to a Nix binary, `ssh://host:/path/to/nix-instantiate` for a remote one,
+        /// or `cmd:<command line>` to run a command like `nix run nixpkgs#nix -- nix-instantiate`
+        #[arg()]
+        nix: String,
+        #[command(flatten)]
+        options: diffing::DiffOptions,
+        /// Periodically write the progress as JSON to this file
+        #[arg(long)]
+        progress_file: Option<PathBuf>,
+    },
+    /// Diff previously captured parser outputs instead of running Nix
+    ImportOutputs {
+        /// Path to the output file, or `-` for stdout
+        #[arg(long, short, default_value = "report.json")]
+        output_file: PathBuf,
+        /// Captured output of the first Nix, read from `<NIX_A_OUT>.stdout`, `.stderr` and `.exit`
+        #[arg()]
+        nix_a_out: PathBuf,
+        /// Captured output of the second Nix, read from `<NIX_B_OUT>.stdout`, `.stderr` and `.exit`
         #[arg()]
-        nix_a: PathBuf,
-        /// Path to a Nix binary
+        nix_b_out: PathBuf,
+        /// The file that was parsed, used for positions without a file
         #[arg()]
-        nix_b: PathBuf,
+        file_label: PathBuf,
+        /// Sort the bindings of attribute sets in the parsed AST before comparing
+        #[arg(long)]
+        canonicalize_ast: bool,
+        /// Also compare differing stdouts as ASTs, and record which of their nodes differ
+        #[arg(long)]
+        ast_diff: bool,
+        /// Compare messages missing on one side as one if their wording is at least this similar
+        #[arg(long)]
+        reword_threshold: Option<f64>,
+        /// Also record the original texts of messages that were simplified before comparing
+        #[arg(long)]
+        keep_raw_messages: bool,
+        /// Name of the first Nix in reports. Defaults to the name of its output files
+        #[arg(long)]
+        label_a: Option<String>,
+        /// Name of the second Nix in reports. Defaults to the name of its output files
+        #[arg(long)]
+        label_b: Option<String>,
+        /// Record how the result was produced, with the command line, under a `provenance` key
+        #[arg(long)]
+        embed_provenance: bool,
+    },
+    /// Re-simplify the messages of a diffing result with other rules, without running Nix again
+    Renormalize {
+        /// Path to the output file, or `-` for stdout
+        #[arg(long, short, default_value = "report.json")]
+        output_file: PathBuf,
+        /// Diffing result to renormalize
+        #[arg()]
+        input: PathBuf,
+        /// JSON list of `{ "pattern": <regex>, "replacement": <string> }` rules, applied in order
+        /// to every message. Replacements can use capture groups like `$1`
+        #[arg()]
+        rules: PathBuf,
     },
     /// Prints a human-readable summary of a Diffing result (generated by NixParse)
     /// Default: auto (detailed with single file, summary for multiple
     Report {
-        /// In which level of detail to print
-        #[arg(long, short, default_value = "")]
-        verbosity: String,
+        #[command(flatten)]
+        options: reporting::ReportOptions,
+        /// Path to the report file
+        #[arg(num_args = 1..)]
+        report_paths: Vec<PathBuf>,
+        #[command(flatten)]
+        post: PostOptions,
+    },
+    /// Compare two diffing results or reports and show which divergences changed
+    ReportDiff {
+        /// The earlier result
+        #[arg()]
+        old: PathBuf,
+        /// The later result
+        #[arg()]
+        new: PathBuf,
+    },
+    /// Flatten diffing results into a Parquet table for analysis with external tools
+    #[cfg(feature = "parquet")]
+    Export {
+        /// Path to the Parquet file to write
+        #[arg(long, short, default_value = "report.parquet")]
+        output_file: PathBuf,
         /// Path to the report file
         #[arg(num_args = 1..)]
         report_paths: Vec<PathBuf>,
     },
+    /// Check that the network, the given Nix binaries and the output directory are usable
+    Doctor {
+        /// Path to a Nix binary, `ssh://host:/path/to/nix-instantiate` for a remote one,
+        /// or `cmd:<command line>` to run a command like `nix run nixpkgs#nix -- nix-instantiate`.
+        /// Can be given multiple times
+        #[arg(long)]
+        nix: Vec<String>,
+        /// Directory that results will be written to
+        #[arg(long, default_value = ".")]
+        output_dir: PathBuf,
+    },
+    /// Check config, rules and repository list files for errors, without indexing or diffing
+    Validate {
+        /// `build-index` config file, JSON or TOML
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// `renormalize` rules file
+        #[arg(long)]
+        rules: Option<PathBuf>,
+        /// List of repositories in the format of `build-index --failures-file`
+        #[arg(long)]
+        repos: Option<PathBuf>,
+    },
+    /// Print the JSON Schema of one of the output formats
+    #[cfg(feature = "schema")]
+    Schema {
+        /// Which format to describe. One of `index`, `diff`, `report`
+        #[arg()]
+        kind: String,
+    },
+}
+
+impl Command {
+    /// Where the command writes its result to, `-` being stdout
+    fn output_path(&self) -> Option<&Path> {
+        match self {
+            Command::BuildIndex { out, .. } => Some(out),
+            Command::NixParse { output_file, .. }
+            | Command::NixBisect { output_file, .. }
+            | Command::RecordCorpus { output_file, .. }
+            | Command::ImportOutputs { output_file, .. }
+            | Command::Renormalize { output_file, .. } => Some(output_file),
+            Command::Report { options, .. } => options.output_file.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Whether the command writes its output to stdout, so that logs have to go elsewhere
+    fn writes_to_stdout(&self) -> bool {
+        match self {
+            Command::Report { options, .. } if options.is_machine_output() => true,
+            _ => self.output_path().is_some_and(progress::is_stdout),
+        }
+    }
+}
+
+/// What to do about output files that already exist
+#[derive(clap::Args, Debug)]
+struct ClobberOptions {
+    /// Replace output files that already exist, instead of failing
+    #[arg(long, global = true)]
+    overwrite: bool,
+}
+
+impl ClobberOptions {
+    /// Fail early if `path` exists and may not be overwritten, before doing any of the work
+    fn check(&self, path: &Path) -> Result<()> {
+        if !self.overwrite && !progress::is_stdout(path) && path.exists() {
+            return Err(eyre!(
+                "{} already exists, pass `--overwrite` to replace it",
+                path.display()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Like [`check`](Self::check) for a directory to write files into, which may exist as long
+    /// as it is empty
+    fn check_dir(&self, dir: &Path) -> Result<()> {
+        let has_files = std::fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some());
+        if !self.overwrite && has_files {
+            return Err(eyre!(
+                "{} is not empty, pass `--overwrite` to write into it anyway",
+                dir.display()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check the files that diffing writes besides its result
+    fn check_diff_outputs(&self, options: &diffing::DiffOptions) -> Result<()> {
+        if let Some(path) = &options.files_tsv {
+            self.check(path)?;
+        }
+        if let Some(dir) = &options.save_raw {
+            self.check_dir(dir)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct PostOptions {
+    /// Additionally send the result as JSON to this URL via POST
+    #[arg(long)]
+    post_results: Option<url::Url>,
+    /// Value of the `Authorization` header to send with `--post-results`
+    #[arg(long, requires = "post_results")]
+    post_auth_header: Option<String>,
+}
+
+impl PostOptions {
+    async fn post<T: serde::Serialize>(&self, result: &T) -> Result<()> {
+        if let Some(url) = &self.post_results {
+            reporting::post_results(url, self.post_auth_header.as_deref(), result).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Fill in the options not given on the command line from a JSON or TOML config file,
+/// whose keys are the names of the fields of `options`
+fn apply_config<T: serde::Serialize + serde::de::DeserializeOwned>(
+    options: &T,
+    path: &Path,
+    matches: &clap::ArgMatches,
+) -> Result<T> {
+    let content = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+    let config: serde_json::Map<String, serde_json::Value> =
+        if path.extension() == Some("json".as_ref()) {
+            serde_json::from_str(&content).map_err(color_eyre::Report::from)
+        } else {
+            toml::from_str(&content).map_err(color_eyre::Report::from)
+        }
+        .wrap_err_with(|| format!("Failed to parse {}", path.display()))?;
+    let serde_json::Value::Object(mut merged) = serde_json::to_value(options)? else {
+        unreachable!("Options are a struct");
+    };
+    for (key, value) in config {
+        if !merged.contains_key(&key) {
+            return Err(eyre!("Unknown option '{}' in {}", key, path.display()));
+        }
+        if matches.value_source(&key) != Some(clap::parser::ValueSource::CommandLine) {
+            merged.insert(key, value);
+        }
+    }
+    serde_json::from_value(serde_json::Value::Object(merged))
+        .wrap_err_with(|| format!("Invalid options in {}", path.display()))
+}
+
+/// Load each of the given files like the command using it would, and report all that fail
+fn validate(config: Option<PathBuf>, rules: Option<PathBuf>, repos: Option<PathBuf>) -> Result<()> {
+    let mut checks: Vec<(PathBuf, Result<()>)> = vec![];
+    if let Some(path) = config {
+        // Start from the defaults, as if nothing was given on the command line
+        let matches =
+            <indexing::IndexOptions as clap::Args>::augment_args(clap::Command::new("build-index"))
+                .get_matches_from(["build-index"]);
+        let check = indexing::IndexOptions::from_arg_matches(&matches)
+            .map_err(color_eyre::Report::from)
+            .and_then(|defaults| apply_config(&defaults, &path, &matches))
+            .and_then(|options| options.validate());
+        checks.push((path, check));
+    }
+    if let Some(path) = rules {
+        let check = diffing::SimplifyRules::from_path(&path).map(|_| ());
+        checks.push((path, check));
+    }
+    if let Some(path) = repos {
+        let check =
+            indexing::count_repos(&path).map(|count| tracing::debug!(count, "Repositories"));
+        checks.push((path, check));
+    }
+    if checks.is_empty() {
+        return Err(eyre!(
+            "Nothing to validate, pass `--config`, `--rules` or `--repos`"
+        ));
+    }
+
+    let mut invalid = 0;
+    for (path, check) in checks {
+        match check {
+            Ok(()) => tracing::info!(path = %path.display(), "Valid"),
+            Err(err) => {
+                tracing::error!(path = %path.display(), "Invalid: {:#}", err);
+                invalid += 1;
+            }
+        }
+    }
+    match invalid {
+        0 => Ok(()),
+        _ => Err(eyre!("{} of the files are invalid", invalid)),
+    }
+}
+
+/// Build `spec` from Nixpkgs if it names an attribute there, naming its side after it unless
+/// `label` is already given. Otherwise it is kept as it is
+async fn resolve_nixpkgs_parser(spec: String, label: &mut Option<String>) -> Result<String> {
+    if !diffing::is_nixpkgs_attr(&spec) {
+        return Ok(spec);
+    }
+    let nix = diffing::build_nixpkgs_parser(&spec).await?;
+    label.get_or_insert(spec);
+    Ok(nix.to_string_lossy().into_owned())
+}
+
+/// Write a diffing result as JSON, falling back to `./report.json` if the output file can't
+/// be created
+fn write_result(
+    output_file: PathBuf,
+    result: &diffing::DiffResult,
+    clobber: &ClobberOptions,
+) -> Result<()> {
+    let json = serde_json::to_string_pretty(result)?;
+    let write = |out: &mut dyn Write| Ok(out.write_all(json.as_bytes())?);
+    if let Err(e) = write_atomically(&output_file, write) {
+        // There is no file to fall back from
+        if progress::is_stdout(&output_file) {
+            return Err(e);
+        }
+        tracing::error!("Error creating file; writing to ./report.json; {:#}", e);
+        let fallback = Path::new("./report.json");
+        clobber.check(fallback)?;
+        write_atomically(fallback, write)?;
+    }
+    Ok(())
+}
+
+/// Write a file via a temporary one next to it, so that a crash never leaves it half-written.
+/// `-` writes to stdout instead
+fn write_atomically(path: &Path, write: impl FnOnce(&mut dyn Write) -> Result<()>) -> Result<()> {
+    if progress::is_stdout(path) {
+        let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+        write(&mut out)?;
+        writeln!(out)?;
+        out.flush()?;
+        return Ok(());
+    }
+    let tmp = progress::temp_file_next_to(path)
+        .wrap_err_with(|| format!("Failed to create {}", path.display()))?;
+    let mut out = std::io::BufWriter::new(tmp.reopen()?);
+    write(&mut out)?;
+    out.flush()?;
+    tmp.persist(path)
+        .wrap_err_with(|| format!("Failed to create {}", path.display()))?;
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
+    let matches = <Args as clap::CommandFactory>::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+    // Keep stdout clean for piping when the output goes there
+    let log_writer = if args.command.writes_to_stdout() {
+        tracing_subscriber::fmt::writer::BoxMakeWriter::new(std::io::stderr)
+    } else {
+        tracing_subscriber::fmt::writer::BoxMakeWriter::new(std::io::stdout)
+    };
     use tracing_subscriber::prelude::*;
     tracing_subscriber::registry()
-        .with(tracing_subscriber::filter::LevelFilter::from_level(
-            tracing::Level::DEBUG,
-        ))
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+                tracing_subscriber::EnvFilter::new(format!(
+                    "warn,{}={}",
+                    env!("CARGO_CRATE_NAME"),
+                    args.min_level
+                ))
+            }),
+        )
         .with(
             tracing_subscriber::fmt::layer()
+                .with_writer(log_writer)
                 .with_target(true)
                 .with_span_events(tracing_subscriber::fmt::format::FmtSpan::NEW),
         )
@@ -77,45 +491,284 @@ async fn main() -> Result<()> {
 
     color_eyre::install()?;
 
-    match Command::parse() {
-        Command::BuildIndex { sources, out } => {
-            use crate::indexing;
-            let sources = if sources.contains('*') {
-                enumset::EnumSet::all()
-            } else {
-                sources
-                    .split(',')
-                    .map(indexing::SourceSet::from_str)
-                    .collect::<std::result::Result<_, ()>>()
-                    .map_err(move |()| eyre!("Invalid source set '{}'", sources))?
-            };
-            indexing::build_index(sources, out).await?;
+    if let Command::BuildIndex {
+        config: Some(config),
+        options,
+        ..
+    } = &mut args.command
+    {
+        *options = apply_config(
+            options,
+            config,
+            matches.subcommand_matches("build-index").unwrap(),
+        )?;
+    }
+    if let Command::BuildIndex { options, .. } = &args.command {
+        if options.skip_lfs {
+            // npins doesn't let us configure its git invocations, but they inherit our
+            // environment. Set before the runtime starts its threads, which makes it safe
+            std::env::set_var("GIT_LFS_SKIP_SMUDGE", "1");
+        }
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(args))
+}
+
+async fn run(args: Args) -> Result<()> {
+    indexing::configure_client(args.user_agent.clone());
+
+    match args.command {
+        Command::BuildIndex { out, options, .. } => {
+            args.clobber.check(&out)?;
+            for path in [&options.timing_log, &options.failures_file]
+                .into_iter()
+                .flatten()
+            {
+                args.clobber.check(path)?;
+            }
+            let progress = progress::Progress::new(options.progress_file.clone());
+            indexing::build_index(out, &options, &progress).await?;
+        }
+        Command::Sources => {
+            for source in enumset::EnumSet::<indexing::SourceSet>::all() {
+                println!("{}\t{}", source.as_str(), source.description());
+            }
+            println!("*\tAll of the above");
         }
         Command::NixParse {
             folder,
             nix_a,
             nix_b,
+            corpus,
+            output_file,
+            mut options,
+            progress_file,
+            embed_provenance,
+            post,
+        } => {
+            args.clobber.check(&output_file)?;
+            args.clobber.check_diff_outputs(&options)?;
+            let (nix_a, nix_b) = if options.parsers_from_nixpkgs {
+                // Without a corpus, NIX_A is the first side, otherwise the second one
+                let label_a = match &nix_b {
+                    Some(_) => &mut options.label_a,
+                    None => &mut options.label_b,
+                };
+                let nix_a = resolve_nixpkgs_parser(nix_a, label_a).await?;
+                let nix_b = match nix_b {
+                    Some(nix_b) => Some(resolve_nixpkgs_parser(nix_b, &mut options.label_b).await?),
+                    None => None,
+                };
+                (nix_a, nix_b)
+            } else {
+                (nix_a, nix_b)
+            };
+            let parse_nix = |nix: String| {
+                diffing::NixCommand::from_str(nix.as_str())
+                    .map_err(move |()| eyre!("Invalid Nix binary '{}'", nix))
+            };
+            let (nix_a, nix_b) = match (corpus, nix_b) {
+                (Some(corpus), _) => (
+                    diffing::NixCommand::Recorded(std::sync::Arc::new(diffing::Corpus::load(
+                        &corpus,
+                    )?)),
+                    parse_nix(nix_a)?,
+                ),
+                (None, Some(nix_b)) => (parse_nix(nix_a)?, parse_nix(nix_b)?),
+                (None, None) => unreachable!("Enforced by clap"),
+            };
+            let positions_format = options.positions_format;
+            let files_tsv = options.files_tsv.is_some();
+            let mut result = diffing::diff_parsers(
+                folder,
+                nix_a,
+                nix_b,
+                options,
+                &progress::Progress::new(progress_file),
+            )
+            .await?;
+            if embed_provenance {
+                result.provenance = Some(provenance::Provenance::current());
+            }
+            positions_format.scope(|| write_result(output_file, &result, &args.clobber))?;
+            post.post(&positions_format.scope(|| serde_json::to_value(&result))?)
+                .await?;
+            // The exit status is the verdict for `git bisect run`, after all outputs are written
+            let diverged = result
+                .file_outcomes
+                .iter()
+                .filter(|(_, outcome)| matches!(outcome, diffing::FileOutcome::Diff))
+                .count();
+            if files_tsv && diverged > 0 {
+                return Err(eyre!("{} of the files diverged", diverged));
+            }
+        }
+        Command::NixBisect {
+            output_file,
+            folder,
+            nix,
+            options,
+            progress_file,
+        } => {
+            args.clobber.check(&output_file)?;
+            args.clobber.check_diff_outputs(&options)?;
+            let mut nix = nix;
+            if options.parsers_from_nixpkgs {
+                for spec in &mut nix {
+                    *spec = resolve_nixpkgs_parser(std::mem::take(spec), &mut None).await?;
+                }
+            }
+            let nixes = nix
+                .iter()
+                .map(|nix| {
+                    diffing::NixCommand::from_str(nix)
+                        .map_err(|()| eyre!("Invalid Nix binary '{}'", nix))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let result = diffing::bisect_parsers(
+                folder,
+                nixes,
+                options,
+                &progress::Progress::new(progress_file),
+            )
+            .await?;
+            for (file, version) in &result.first_divergence {
+                let line = format!(
+                    "{}: diverged at {} → {}",
+                    file.display(),
+                    result.versions[version - 1],
+                    result.versions[*version]
+                );
+                // Logs go to stderr when the result itself goes to stdout
+                if progress::is_stdout(&output_file) {
+                    tracing::info!("{}", line);
+                } else {
+                    println!("{}", line);
+                }
+            }
+            write_atomically(&output_file, |out| {
+                Ok(serde_json::to_writer_pretty(out, &result)?)
+            })?;
+        }
+        Command::RecordCorpus {
+            output_file,
+            folder,
+            nix,
+            options,
+            progress_file,
+        } => {
+            args.clobber.check(&output_file)?;
+            args.clobber.check_diff_outputs(&options)?;
+            let nix = diffing::NixCommand::from_str(nix.as_str())
+                .map_err(move |()| eyre!("Invalid Nix binary '{}'", nix))?;
+            let corpus = diffing::record_corpus(
+                folder,
+                nix,
+                options,
+                &progress::Progress::new(progress_file),
+            )
+            .await?;
+            write_atomically(&output_file, |out| Ok(serde_json::to_writer(out, &corpus)?))?;
+        }
+        Command::ImportOutputs {
+            output_file,
+            nix_a_out,
+            nix_b_out,
+            file_label,
+            canonicalize_ast,
+            ast_diff,
+            reword_threshold,
+            keep_raw_messages,
+            label_a,
+            label_b,
+            embed_provenance,
+        } => {
+            args.clobber.check(&output_file)?;
+            let options = diffing::DiffOptions {
+                canonicalize_ast,
+                ast_diff,
+                reword_threshold,
+                keep_raw_messages,
+                label_a,
+                label_b,
+                ..Default::default()
+            };
+            let mut result = diffing::diff_captured(&nix_a_out, &nix_b_out, file_label, &options)?;
+            if embed_provenance {
+                result.provenance = Some(provenance::Provenance::current());
+            }
+            write_result(output_file, &result, &args.clobber)?;
+        }
+        Command::Renormalize {
             output_file,
+            input,
+            rules,
         } => {
-            let result = diffing::diff_parsers(folder, nix_a, nix_b).await?;
-            let mut out_file_attempt = File::create(output_file);
-            let mut out_file = out_file_attempt.unwrap_or_else(|e| {
-                tracing::error!("Error creating file; writing to ./report.json; {}", e);
-                File::create("./report.json").unwrap()
-            });
-            out_file.write_all(
-                serde_json::to_string_pretty(&result)?
-                    .into_bytes()
-                    .as_slice(),
+            args.clobber.check(&output_file)?;
+            let rules = diffing::SimplifyRules::from_path(&rules)?;
+            let result: diffing::DiffResult = serde_json::from_str(
+                &std::fs::read_to_string(&input)
+                    .wrap_err_with(|| format!("Failed to read {}", input.display()))?,
+            )
+            .wrap_err_with(|| format!("Failed to parse {}", input.display()))?;
+            write_result(
+                output_file,
+                &diffing::renormalize(result, &rules),
+                &args.clobber,
             )?;
         }
         Command::Report {
-            verbosity,
+            options,
+            report_paths,
+            post,
+        } => {
+            if let Some(output_file) = &options.output_file {
+                args.clobber.check(output_file)?;
+            }
+            let report = report(report_paths, &options)?;
+            if let Some(output_file) = &options.output_file {
+                write_atomically(output_file, |out| {
+                    Ok(serde_json::to_writer_pretty(out, &report)?)
+                })?;
+            }
+            post.post(&report).await?;
+        }
+        Command::ReportDiff { old, new } => {
+            reporting::report_diff(old, new)?;
+        }
+        #[cfg(feature = "parquet")]
+        Command::Export {
+            output_file,
             report_paths,
         } => {
-            let verbosity = ReportVerbosity::from_str(verbosity.as_str())
-                .map_err(move |()| eyre!("Invalid verbosity '{}'", verbosity))?;
-            report(report_paths, verbosity)?;
+            args.clobber.check(&output_file)?;
+            export::export_parquet(report_paths, output_file)?;
+        }
+        Command::Doctor { nix, output_dir } => {
+            let nix = nix
+                .iter()
+                .map(|nix| {
+                    diffing::NixCommand::from_str(nix)
+                        .map_err(|()| eyre!("Invalid Nix binary '{}'", nix))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            doctor::doctor(nix, &output_dir).await?;
+        }
+        Command::Validate {
+            config,
+            rules,
+            repos,
+        } => {
+            validate(config, rules, repos)?;
+        }
+        #[cfg(feature = "schema")]
+        Command::Schema { kind } => {
+            let kind = schema::SchemaKind::from_str(kind.as_str())
+                .map_err(move |()| eyre!("Invalid schema kind '{}'", kind))?;
+            schema::print_schema(kind)?;
         }
     }
     Ok(())
@@ -0,0 +1,153 @@
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often the progress file gets rewritten
+const WRITE_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize)]
+struct State {
+    stage: String,
+    done: u64,
+    total: Option<u64>,
+    /// Start of the current stage, in seconds since the epoch
+    started_at: u64,
+    /// Estimated seconds until the current stage is done
+    eta: Option<u64>,
+    /// Whether the command is over, successfully or not. Nothing is written after that
+    finished: bool,
+}
+
+/// The state shared by all handles, with the file it is written to
+struct Shared {
+    state: Mutex<State>,
+    path: PathBuf,
+}
+
+impl Shared {
+    /// A copy of the state with an up to date estimate, to write it without holding the lock
+    fn snapshot(&self) -> State {
+        let mut state = self.state.lock().unwrap();
+        let elapsed = now().saturating_sub(state.started_at);
+        state.eta = match state.total {
+            Some(total) if state.done > 0 => {
+                Some(elapsed * total.saturating_sub(state.done) / state.done)
+            }
+            _ => None,
+        };
+        state.clone()
+    }
+
+    fn write(&self, state: &State) {
+        if let Err(err) = write_state(state, &self.path) {
+            tracing::warn!(
+                err = %err,
+                path = %self.path.display(),
+                "Failed to write progress file"
+            );
+        }
+    }
+}
+
+impl Drop for Shared {
+    /// Write the final state once all handles are gone, so that monitors don't keep seeing
+    /// the last periodic one
+    fn drop(&mut self) {
+        let mut state = self.snapshot();
+        state.finished = true;
+        self.write(&state);
+    }
+}
+
+/// Progress of a long-running command, periodically written to a file for external monitors.
+/// All methods are no-ops when no progress file was requested.
+#[derive(Clone, Default)]
+pub struct Progress(Option<Arc<Shared>>);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Whether `path` is `-`, which stands for stdout as an output path
+pub fn is_stdout(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// A temporary file in the directory of `path`, to be persisted over it once it is complete.
+/// Unlike usual temporary files, it can be read by everyone the umask allows
+pub fn temp_file_next_to(path: &Path) -> std::io::Result<tempfile::NamedTempFile> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A bare file name has an empty parent, which is the current directory
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    tempfile::Builder::new()
+        .permissions(std::fs::Permissions::from_mode(0o666))
+        .tempfile_in(dir)
+}
+
+/// Replace the file atomically, so that readers never see a partial write
+fn write_state(state: &State, path: &Path) -> std::io::Result<()> {
+    let tmp = temp_file_next_to(path)?;
+    serde_json::to_writer(tmp.as_file(), state)?;
+    tmp.persist(path)?;
+    Ok(())
+}
+
+impl Progress {
+    /// Start writing progress to `path`, if given. Must be called within a Tokio runtime.
+    pub fn new(path: Option<PathBuf>) -> Progress {
+        let Some(path) = path else {
+            return Progress(None);
+        };
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                stage: "starting".into(),
+                done: 0,
+                total: None,
+                started_at: now(),
+                eta: None,
+                finished: false,
+            }),
+            path,
+        });
+        let weak: Weak<Shared> = Arc::downgrade(&shared);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(WRITE_INTERVAL);
+            // Stop once all handles are gone
+            while let Some(shared) = {
+                interval.tick().await;
+                weak.upgrade()
+            } {
+                let state = shared.snapshot();
+                // Moving `shared` along delays the final write of the last handle after this one
+                let _ = tokio::task::spawn_blocking(move || shared.write(&state)).await;
+            }
+        });
+        Progress(Some(shared))
+    }
+
+    /// Begin a new stage, optionally with a known number of steps
+    pub fn stage(&self, stage: &str, total: Option<u64>) {
+        if let Some(shared) = &self.0 {
+            let mut state = shared.state.lock().unwrap();
+            state.stage = stage.to_string();
+            state.done = 0;
+            state.total = total;
+            state.started_at = now();
+        }
+    }
+
+    /// Mark one step of the current stage as done
+    pub fn inc(&self) {
+        if let Some(shared) = &self.0 {
+            shared.state.lock().unwrap().done += 1;
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Flags whose values are credentials, which are never recorded
+const SECRET_FLAGS: &[&str] = &["--post-auth-header"];
+
+/// How a result was produced, to audit it or to run the same comparison again from it alone
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Provenance {
+    /// The full command line, starting with the binary. Values of credentials are redacted
+    pub command: Vec<String>,
+    /// Directory the command ran in, which its relative paths are relative to
+    pub cwd: Option<PathBuf>,
+    /// Version of flaker
+    pub version: String,
+    /// When the result was written, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// Name of the machine the command ran on, if known
+    pub host: Option<String>,
+}
+
+impl Provenance {
+    /// The provenance of the running command
+    pub fn current() -> Provenance {
+        Provenance {
+            command: redact(std::env::args_os().map(|arg| arg.to_string_lossy().into_owned())),
+            cwd: std::env::current_dir().ok(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            host: hostname(),
+        }
+    }
+}
+
+/// Replace the values of [`SECRET_FLAGS`], given either as `--flag value` or as `--flag=value`
+fn redact(args: impl Iterator<Item = String>) -> Vec<String> {
+    let mut redact_next = false;
+    args.map(|arg| {
+        if std::mem::take(&mut redact_next) {
+            return "<redacted>".to_string();
+        }
+        match arg.split_once('=') {
+            Some((flag, _)) if SECRET_FLAGS.contains(&flag) => format!("{}=<redacted>", flag),
+            _ => {
+                redact_next = SECRET_FLAGS.contains(&arg.as_str());
+                arg
+            }
+        }
+    })
+    .collect()
+}
+
+fn hostname() -> Option<String> {
+    // Only Linux has it in procfs, shells elsewhere usually export it
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+}
@@ -1,13 +1,18 @@
-use crate::diffing::{Diff, DiffResult, Message, MessageOccurrences, Position};
+use crate::diffing::{
+    AstChange, Diff, DiffResult, FileClass, FileOutcome, Labels, Message, MessageOccurrences,
+    Position,
+};
 use crate::indexing::SourceSet;
 use crate::reporting::ReportVerbosity::{Auto, Detailed, Summary};
 use color_eyre::eyre::{eyre, Context, Result};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Copy)]
 pub enum ReportVerbosity {
@@ -31,35 +36,429 @@ impl FromStr for ReportVerbosity {
     }
 }
 
+/// Level of detail of each message category in the text report
+#[derive(Debug, Clone, Copy)]
+struct CategoryVerbosity {
+    err: ReportVerbosity,
+    wrn: ReportVerbosity,
+    trc: ReportVerbosity,
+    res: ReportVerbosity,
+}
+
+impl CategoryVerbosity {
+    /// The verbosities given per category, with `verbosity` for those left on `auto`
+    fn from_options(options: &ReportOptions, verbosity: ReportVerbosity) -> Result<Self> {
+        let parse = |s: &str| match ReportVerbosity::from_str(s) {
+            Ok(Auto) => Ok(verbosity),
+            Ok(v) => Ok(v),
+            Err(()) => Err(eyre!("Invalid verbosity '{}'", s)),
+        };
+        Ok(CategoryVerbosity {
+            err: parse(&options.err_verbosity)?,
+            wrn: parse(&options.wrn_verbosity)?,
+            trc: parse(&options.trc_verbosity)?,
+            res: parse(&options.res_verbosity)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ReportLayout {
+    /// Nested by category, message and repo
+    Tree,
+    /// One tab-separated line per category, message and repo
+    Flat,
+}
+
+impl FromStr for ReportLayout {
+    type Err = ();
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "tree" => Ok(ReportLayout::Tree),
+            "flat" => Ok(ReportLayout::Flat),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ReportFormat {
+    /// Human-readable, see [`ReportLayout`]
+    Text,
+    /// JUnit XML on stdout, for CI systems
+    Junit,
+    /// A tree drawn with box-drawing characters on stdout, fitted to the terminal width
+    TerminalTree,
+    /// A self-contained HTML page on stdout. Detailed reports of a single repo link
+    /// every position to a snippet of its source
+    Html,
+}
+
+impl FromStr for ReportFormat {
+    type Err = ();
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "text" => Ok(ReportFormat::Text),
+            "junit" => Ok(ReportFormat::Junit),
+            "terminal-tree" => Ok(ReportFormat::TerminalTree),
+            "html" => Ok(ReportFormat::Html),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ReportSort {
+    /// Alphabetically by message
+    Name,
+    /// Most occurrences over all repos first
+    Count,
+}
+
+impl FromStr for ReportSort {
+    type Err = ();
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "name" => Ok(ReportSort::Name),
+            "count" => Ok(ReportSort::Count),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ReportOptions {
+    /// Output format: `text`, `terminal-tree`, `html`, or `junit` for JUnit XML with one test suite per repo
+    #[arg(long, default_value = "text")]
+    pub format: String,
+    /// In which level of detail to print
+    #[arg(long, short, default_value = "")]
+    pub verbosity: String,
+    /// Level of detail of the error messages in the text report. Defaults to `--verbosity`
+    #[arg(long, default_value = "")]
+    pub err_verbosity: String,
+    /// Level of detail of the warning messages in the text report. Defaults to `--verbosity`
+    #[arg(long, default_value = "")]
+    pub wrn_verbosity: String,
+    /// Level of detail of the trace messages in the text report. Defaults to `--verbosity`
+    #[arg(long, default_value = "")]
+    pub trc_verbosity: String,
+    /// Level of detail of the eval results in the text report. Defaults to `--verbosity`
+    #[arg(long, default_value = "")]
+    pub res_verbosity: String,
+    /// Only print a single line with the number of diffs per category
+    #[arg(long)]
+    pub count_only: bool,
+    /// Only print the N messages that diverge in the most repos, as tab-separated
+    /// `repos category message` lines
+    #[arg(long, value_name = "N")]
+    pub top: Option<usize>,
+    /// How to lay out the report: `tree`, or `flat` for tab-separated
+    /// `category message repo count` lines on stdout
+    #[arg(long, default_value = "tree")]
+    pub layout: String,
+    /// JSON file with a list of `{ "pattern": <regex>, "severity": "error"|"warn"|"trace" }` rules.
+    /// Messages matching a pattern are reported under that severity instead of Nix' own one
+    #[arg(long)]
+    pub severity_rules: Option<PathBuf>,
+    /// Skip result files that can't be loaded instead of failing, and list them at the end
+    #[arg(long)]
+    pub keep_going: bool,
+    /// Order of the messages: `name`, or `count` for the most frequent ones first
+    #[arg(long, default_value = "name")]
+    pub sort_by: String,
+    /// Directory that relative positions are found in, for the source snippets of `--format html`.
+    /// Defaults to the current directory
+    #[arg(long)]
+    pub source_root: Option<PathBuf>,
+    /// Print nothing at all when no parser output diverged, for CI jobs that should stay quiet
+    #[arg(long)]
+    pub diff_only: bool,
+    /// In summary verbosity, show the original text and one position for the first N messages
+    /// of each category, to recognize them without the full detail
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub examples: usize,
+    /// Leave out the messages with fewer than N positions over all repos, to focus on the
+    /// widespread divergences first
+    #[arg(long, value_name = "N")]
+    pub min_occurrences: Option<usize>,
+    /// Leave out the messages matching this regex, for known and accepted divergences.
+    /// Can be given multiple times
+    #[arg(long, value_name = "REGEX")]
+    pub exclude_message: Vec<String>,
+    /// File with one `--exclude-message` regex per line. Empty lines and lines starting with `#`
+    /// are ignored
+    #[arg(long, value_name = "FILE")]
+    pub exclude_messages_from: Option<PathBuf>,
+    /// With `--format html`, write the report to a file and open it in the browser instead of
+    /// printing it. When not run from a terminal, the file is only logged
+    #[arg(long)]
+    pub open: bool,
+    /// Also write the aggregated report as JSON to this file. With `-`, it is written to stdout
+    /// instead of printing the report
+    #[arg(long, short)]
+    pub output_file: Option<PathBuf>,
+}
+
+impl ReportOptions {
+    /// Whether the report goes to stdout for other programs to read, which log lines in
+    /// between would break
+    pub fn is_machine_output(&self) -> bool {
+        let document = match ReportFormat::from_str(&self.format) {
+            Ok(ReportFormat::Junit) => true,
+            // With `--open`, the page goes to a temporary file instead
+            Ok(ReportFormat::Html) => !self.open,
+            _ => false,
+        };
+        document
+            || self.count_only
+            || self.top.is_some()
+            || matches!(ReportLayout::from_str(&self.layout), Ok(ReportLayout::Flat))
+    }
+}
+
+/// Compile the `--exclude-message` patterns, including the ones from `--exclude-messages-from`
+fn exclude_patterns(options: &ReportOptions) -> Result<Vec<Regex>> {
+    let mut patterns = options.exclude_message.clone();
+    if let Some(path) = &options.exclude_messages_from {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        patterns.extend(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string),
+        );
+    }
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern).with_context(|| format!("Invalid pattern '{}'", pattern))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Error,
+    Warn,
+    Trace,
+}
+
+/// Reclassification of messages into a different severity, first match wins
+struct SeverityRules(Vec<(Regex, Severity)>);
+
+impl SeverityRules {
+    fn from_path(path: &Path) -> Result<SeverityRules> {
+        #[derive(Deserialize)]
+        struct Rule {
+            pattern: String,
+            severity: Severity,
+        }
+        let rules: Vec<Rule> = serde_json::from_reader(std::io::BufReader::new(
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+        ))
+        .with_context(|| format!("Failed to parse severity rules {}", path.display()))?;
+        rules
+            .into_iter()
+            .map(|Rule { pattern, severity }| {
+                Regex::new(&pattern)
+                    .with_context(|| format!("Invalid pattern '{}'", pattern))
+                    .map(|re| (re, severity))
+            })
+            .collect::<Result<_>>()
+            .map(SeverityRules)
+    }
+
+    fn severity(&self, msg: &str) -> Option<Severity> {
+        self.0
+            .iter()
+            .find(|(re, _)| re.is_match(msg))
+            .map(|(_, severity)| *severity)
+    }
+}
+
 impl DiffResult {
     fn from_path(path: &PathBuf) -> Result<DiffResult> {
-        let mut report_file = File::open(path)?;
-        let mut content = String::new();
-        report_file.read_to_string(&mut content)?;
-        let res: DiffResult = serde_json::from_str(content.as_str())?;
+        let report_file = File::open(path)?;
+        let res: DiffResult = serde_json::from_reader(std::io::BufReader::new(report_file))?;
         Ok(res)
     }
 }
 
 /// repo -> stdout_diffs
-type OutAnalysis = HashMap<String, HashSet<Diff<Message>>>;
+pub(crate) type OutAnalysis = HashMap<String, HashSet<Diff<Message>>>;
+/// repo -> positions
+pub(crate) type RepoPositions = HashMap<String, Diff<HashSet<Position>>>;
 /// Message -> (repo -> positions)
-type MessageAnalysis = HashMap<Message, HashMap<String, Diff<HashSet<Position>>>>;
+pub(crate) type MessageAnalysis = HashMap<Message, RepoPositions>;
 
 #[derive(Default, Debug, Serialize, Deserialize)]
-struct Report {
-    stdout: OutAnalysis,
-    err_log: MessageAnalysis,
-    wrn_log: MessageAnalysis,
-    trc_log: MessageAnalysis,
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub(crate) struct Report {
+    pub(crate) stdout: OutAnalysis,
+    /// repo -> differing nodes of the stdout ASTs, per file
+    #[serde(default)]
+    pub(crate) ast: HashMap<String, Vec<(PathBuf, Vec<AstChange>)>>,
+    pub(crate) err_log: MessageAnalysis,
+    pub(crate) wrn_log: MessageAnalysis,
+    pub(crate) trc_log: MessageAnalysis,
+    #[serde(default)]
+    pub(crate) res_log: MessageAnalysis,
+    /// repo -> per-file outcomes
+    #[serde(default)]
+    pub(crate) file_outcomes: HashMap<String, Vec<(PathBuf, FileOutcome)>>,
+    /// Names of the two compared parsers, shared by all repos
+    #[serde(default)]
+    pub(crate) labels: Labels,
+    /// repo -> messages that only differ in wording
+    #[serde(default)]
+    pub(crate) reworded: HashMap<String, HashSet<Diff<Message>>>,
+    /// repo -> total number of positions of the messages whose positions are only a sample
+    #[serde(default)]
+    pub(crate) truncated: HashMap<String, HashMap<Message, Diff<usize>>>,
+    /// Original texts of simplified messages, over all repos
+    #[serde(default)]
+    pub(crate) raw_messages: HashMap<Message, HashSet<Message>>,
+    /// repo -> wall time of both parsers on the files where one was much slower
+    #[serde(default)]
+    pub(crate) perf: HashMap<String, Vec<(PathBuf, Diff<Duration>)>>,
+    /// Which parsers emitted each message at all, over all repos
+    #[serde(default)]
+    pub(crate) emitted: HashMap<Message, Diff<bool>>,
+    #[serde(skip)]
+    messages: Interner,
+}
+
+/// Pool of message texts, so that the same message of thousands of repos is only stored once
+#[derive(Default, Debug)]
+struct Interner(HashSet<Message>);
+
+impl Interner {
+    fn intern(&mut self, msg: Message) -> Message {
+        match self.0.get(&msg) {
+            Some(interned) => interned.clone(),
+            None => {
+                self.0.insert(msg.clone());
+                msg
+            }
+        }
+    }
 }
 
 impl Report {
+    /// All message categories, with their names for machine-readable output
+    fn categories(&self) -> [(&'static str, &MessageAnalysis); 4] {
+        [
+            ("err", &self.err_log),
+            ("warn", &self.wrn_log),
+            ("trace", &self.trc_log),
+            ("result", &self.res_log),
+        ]
+    }
+
+    /// Whether the parsers diverged anywhere, in their output or messages
+    fn has_diffs(&self) -> bool {
+        self.stdout.values().any(|diffs| !diffs.is_empty())
+            || self.categories().iter().any(|(_, log)| !log.is_empty())
+            || self.reworded.values().any(|pairs| !pairs.is_empty())
+    }
+
+    fn log_mut(&mut self, severity: Severity) -> &mut MessageAnalysis {
+        match severity {
+            Severity::Error => &mut self.err_log,
+            Severity::Warn => &mut self.wrn_log,
+            Severity::Trace => &mut self.trc_log,
+        }
+    }
+
+    /// Move messages into the category of their effective severity
+    fn apply_severity_rules(&mut self, rules: &SeverityRules) {
+        for from in [Severity::Error, Severity::Warn, Severity::Trace] {
+            for (msg, repos) in std::mem::take(self.log_mut(from)) {
+                let to = rules.severity(&msg).unwrap_or(from);
+                let entry = self.log_mut(to).entry(msg).or_default();
+                for (repo, diff) in repos {
+                    let merged = entry.entry(repo).or_default();
+                    merged.result_a.extend(diff.result_a);
+                    merged.result_b.extend(diff.result_b);
+                }
+            }
+        }
+    }
+
+    /// Drop the messages with fewer than `min` positions, returning how many were dropped
+    fn drop_rare_messages(&mut self, min: usize) -> usize {
+        let mut logs = [
+            std::mem::take(&mut self.err_log),
+            std::mem::take(&mut self.wrn_log),
+            std::mem::take(&mut self.trc_log),
+            std::mem::take(&mut self.res_log),
+        ];
+        let mut dropped = 0;
+        for log in &mut logs {
+            log.retain(|msg, repos| {
+                let positions: usize = repos
+                    .iter()
+                    .map(|(repo, diffs)| {
+                        let counts = self.position_counts(msg, repo, diffs);
+                        counts.result_a + counts.result_b
+                    })
+                    .sum();
+                dropped += usize::from(positions < min);
+                positions >= min
+            });
+        }
+        [self.err_log, self.wrn_log, self.trc_log, self.res_log] = logs;
+        dropped
+    }
+
+    /// Drop the messages matching any of the patterns from all categories, including reworded
+    /// pairs with either side matching, returning how many messages were dropped
+    fn drop_excluded_messages(&mut self, patterns: &[Regex]) -> usize {
+        let excluded = |msg: &str| patterns.iter().any(|re| re.is_match(msg));
+        let mut dropped = 0;
+        for log in [
+            &mut self.err_log,
+            &mut self.wrn_log,
+            &mut self.trc_log,
+            &mut self.res_log,
+        ] {
+            log.retain(|msg, _| {
+                dropped += usize::from(excluded(msg));
+                !excluded(msg)
+            });
+        }
+        for pairs in self.reworded.values_mut() {
+            pairs.retain(|pair| !excluded(&pair.result_a) && !excluded(&pair.result_b));
+        }
+        self.reworded.retain(|_, pairs| !pairs.is_empty());
+        dropped
+    }
+
     fn add(&mut self, diff_result: DiffResult, name: String) {
-        let propagate_msg = |log: &mut MessageAnalysis, occ: MessageOccurrences| {
+        if diff_result.files_scanned == Some(0) {
+            tracing::warn!(
+                repo = name,
+                "No files were diffed, the result is empty for that reason"
+            );
+        }
+        for msg in diff_result.messages() {
+            let by = diff_result.emitted_by(msg);
+            let entry = self
+                .emitted
+                .entry(self.messages.intern(msg.clone()))
+                .or_default();
+            entry.result_a |= by.result_a;
+            entry.result_b |= by.result_b;
+        }
+        let messages = &mut self.messages;
+        let mut propagate_msg = |log: &mut MessageAnalysis, occ: MessageOccurrences| {
             for (msg, d) in occ {
                 let mut di = log
-                    .entry(msg.clone())
+                    .entry(messages.intern(msg))
                     .or_insert(Default::default())
                     .entry(name.clone())
                     .or_insert(Default::default());
@@ -70,16 +469,662 @@ impl Report {
         propagate_msg(&mut self.err_log, diff_result.err_diff);
         propagate_msg(&mut self.wrn_log, diff_result.wrn_diff);
         propagate_msg(&mut self.trc_log, diff_result.trc_diff);
+        propagate_msg(&mut self.res_log, diff_result.res_diff);
         self.stdout.insert(name.clone(), diff_result.stdout_diff);
+        if !diff_result.ast_diff.is_empty() {
+            self.ast.insert(name.clone(), diff_result.ast_diff);
+        }
+        self.file_outcomes
+            .insert(name.clone(), diff_result.file_outcomes);
+        if !diff_result.perf_diff.is_empty() {
+            self.perf.insert(name.clone(), diff_result.perf_diff);
+        }
+        let messages = &mut self.messages;
+        if !diff_result.reworded.is_empty() {
+            let reworded = diff_result
+                .reworded
+                .into_iter()
+                .map(|pair| Diff {
+                    result_a: messages.intern(pair.result_a),
+                    result_b: messages.intern(pair.result_b),
+                })
+                .collect();
+            self.reworded.insert(name.clone(), reworded);
+        }
+        if !diff_result.truncated.is_empty() {
+            let truncated = diff_result
+                .truncated
+                .into_iter()
+                .map(|(msg, counts)| (messages.intern(msg), counts))
+                .collect();
+            self.truncated.insert(name.clone(), truncated);
+        }
+        for (msg, raw) in diff_result.raw_messages {
+            self.raw_messages
+                .entry(messages.intern(msg))
+                .or_default()
+                .extend(raw.into_iter().map(|raw| messages.intern(raw)));
+        }
+    }
+}
+
+/// Totals over a [`Report`], for one-line summaries
+#[derive(Default, Debug)]
+struct ReportStats {
+    /// Number of distinct stdout diffs, summed over all repos
+    stdout: usize,
+    /// Number of divergent messages per category
+    err: usize,
+    wrn: usize,
+    trc: usize,
+    repos: usize,
+}
+
+impl std::fmt::Display for ReportStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "flaker: {} stdout, {} err, {} warn, {} trace diffs across {} repos",
+            self.stdout, self.err, self.wrn, self.trc, self.repos
+        )
+    }
+}
+
+impl Report {
+    /// Number of positions of a message in a repo, including those left out of the sample
+    fn position_counts(
+        &self,
+        msg: &Message,
+        repo: &String,
+        diffs: &Diff<HashSet<Position>>,
+    ) -> Diff<usize> {
+        self.truncated
+            .get(repo)
+            .and_then(|truncated| truncated.get(msg))
+            .copied()
+            .unwrap_or(Diff {
+                result_a: diffs.result_a.len(),
+                result_b: diffs.result_b.len(),
+            })
+    }
+
+    /// Iterate the messages of a log in the requested order
+    fn sorted_messages<'a>(
+        &self,
+        log: &'a MessageAnalysis,
+        sort: ReportSort,
+    ) -> Vec<(&'a Message, &'a RepoPositions)> {
+        let mut messages = sorted(log);
+        if let ReportSort::Count = sort {
+            // Including the positions left out of the sample, as those are just as frequent
+            let count = |msg: &Message, repo_info: &RepoPositions| -> usize {
+                repo_info
+                    .iter()
+                    .map(|(repo, diffs)| {
+                        let counts = self.position_counts(msg, repo, diffs);
+                        counts.result_a + counts.result_b
+                    })
+                    .sum()
+            };
+            // Stable, so equally frequent messages stay ordered by name
+            messages.sort_by_key(|(msg, repo_info)| std::cmp::Reverse(count(msg, repo_info)));
+        }
+        messages
+    }
+
+    /// The messages still in the report that only one side emitted, in any repo
+    fn one_sided(&self) -> Diff<BTreeSet<&Message>> {
+        let mut one_sided = Diff::<BTreeSet<&Message>>::default();
+        for (msg, emitted) in &self.emitted {
+            if !self
+                .categories()
+                .iter()
+                .any(|(_, log)| log.contains_key(msg))
+            {
+                continue;
+            }
+            match (emitted.result_a, emitted.result_b) {
+                (true, false) => one_sided.result_a.insert(msg),
+                (false, true) => one_sided.result_b.insert(msg),
+                _ => false,
+            };
+        }
+        one_sided
+    }
+
+    fn stats(&self) -> ReportStats {
+        ReportStats {
+            stdout: self.stdout.values().map(HashSet::len).sum(),
+            err: self.err_log.len(),
+            wrn: self.wrn_log.len(),
+            trc: self.trc_log.len(),
+            repos: self.stdout.len(),
+        }
     }
 }
 
-fn print_report(report: Report, verbosity: ReportVerbosity) {
+/// Order both sides' positions by file and line, for rendering
+fn sorted_positions(diffs: &Diff<HashSet<Position>>) -> Diff<BTreeSet<&Position>> {
+    Diff {
+        result_a: diffs.result_a.iter().collect(),
+        result_b: diffs.result_b.iter().collect(),
+    }
+}
+
+/// Iterate a map ordered by key, so that output is stable between runs
+fn sorted<K: Ord, V>(map: &HashMap<K, V>) -> Vec<(&K, &V)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries
+}
+
+/// Escape a value for use as a field in tab-separated output
+fn escape_field(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn print_flat_report(report: &Report, sort: ReportSort) {
+    for (repo, out_diffs) in sorted(&report.stdout) {
+        if !out_diffs.is_empty() {
+            println!("stdout\t\t{}\t{}", escape_field(repo), out_diffs.len());
+        }
+    }
+    for (category, log) in report.categories() {
+        for (msg, repo_info) in report.sorted_messages(log, sort) {
+            for (repo, diffs) in sorted(repo_info) {
+                let counts = report.position_counts(msg, repo, diffs);
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    category,
+                    escape_field(msg),
+                    escape_field(repo),
+                    counts.result_a + counts.result_b
+                );
+            }
+        }
+    }
+}
+
+/// Print the `n` messages that diverge in the most repos, over all categories
+fn print_top_messages(report: &Report, n: usize) {
+    let mut messages: Vec<(usize, &str, &Message)> = report
+        .categories()
+        .into_iter()
+        .flat_map(|(category, log)| {
+            log.iter()
+                .map(move |(msg, repo_info)| (repo_info.len(), category, msg))
+        })
+        .collect();
+    // Ties are broken by name, so that the output is stable between runs
+    messages.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.2.cmp(b.2)));
+    for (repos, category, msg) in messages.into_iter().take(n) {
+        println!("{}\t{}\t{}", repos, category, escape_field(msg));
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Every repo is a test suite, every divergent message in it a failed test case
+fn print_junit_report(report: &Report, sort: ReportSort) {
+    println!(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    println!(r#"<testsuites name="flaker">"#);
+    for (repo, out_diffs) in sorted(&report.stdout) {
+        // (test case name, failure details)
+        let mut failures: Vec<(String, String)> = vec![];
+        if !out_diffs.is_empty() {
+            failures.push(("stdout".into(), format!("{:#?}", out_diffs)));
+        }
+        for (category, log) in report.categories() {
+            for (msg, repo_info) in report.sorted_messages(log, sort) {
+                if let Some(diffs) = repo_info.get(repo) {
+                    failures.push((
+                        format!("{}: {}", category, msg),
+                        format!("{:#?}", sorted_positions(diffs)),
+                    ));
+                }
+            }
+        }
+
+        let repo = escape_xml(repo);
+        println!(
+            r#"  <testsuite name="{}" tests="{}" failures="{}">"#,
+            repo,
+            failures.len().max(1),
+            failures.len()
+        );
+        if failures.is_empty() {
+            println!(
+                r#"    <testcase name="no divergences" classname="{}"/>"#,
+                repo
+            );
+        }
+        for (name, details) in failures {
+            let name = escape_xml(&name);
+            println!(r#"    <testcase name="{}" classname="{}">"#, name, repo);
+            println!(
+                r#"      <failure message="{}">{}</failure>"#,
+                name,
+                escape_xml(&details)
+            );
+            println!("    </testcase>");
+        }
+        println!("  </testsuite>");
+    }
+    println!("</testsuites>");
+}
+
+/// Lines of source shown around a position in HTML reports
+const SNIPPET_CONTEXT: usize = 3;
+
+/// What the snippets of HTML reports are highlighted with
+struct Highlighting {
+    syntaxes: syntect::parsing::SyntaxSet,
+    theme: syntect::highlighting::Theme,
+}
+
+static HIGHLIGHTING: LazyLock<Highlighting> = LazyLock::new(|| {
+    let mut syntaxes = syntect::parsing::SyntaxSetBuilder::new();
+    syntaxes.add(
+        syntect::parsing::SyntaxDefinition::load_from_str(
+            include_str!("nix.sublime-syntax"),
+            true,
+            None,
+        )
+        .expect("The Nix grammar is valid"),
+    );
+    let theme = syntect::highlighting::ThemeSet::load_defaults()
+        .themes
+        .remove("InspiredGitHub")
+        .expect("syntect ships this theme");
+    Highlighting {
+        syntaxes: syntaxes.build(),
+        theme,
+    }
+});
+
+/// Render the lines around `pos` highlighted, with its line marked, if its file can be read.
+/// Colors are inline styles, so that the report stays a single file
+fn html_snippet(pos: &Position, source_root: &Path) -> Option<String> {
+    use syntect::html::{styled_line_to_highlighted_html, IncludeBackground};
+
+    let line = pos.line? as usize;
+    let source = std::fs::read_to_string(source_root.join(&pos.file)).ok()?;
+    // The file may have changed since it was diffed
+    if source.lines().count() < line {
+        return None;
+    }
+    let first = line.saturating_sub(SNIPPET_CONTEXT).max(1);
+    let highlighting = &*HIGHLIGHTING;
+    let mut highlighter = syntect::easy::HighlightLines::new(
+        &highlighting.syntaxes.syntaxes()[0],
+        &highlighting.theme,
+    );
+    let mut snippet = String::new();
+    for (number, text) in syntect::util::LinesWithEndings::from(&source)
+        .enumerate()
+        .map(|(i, text)| (i + 1, text))
+        .take(line + SNIPPET_CONTEXT)
+    {
+        // The lines before still matter, e.g. when the snippet starts within a string
+        let regions = highlighter
+            .highlight_line(text, &highlighting.syntaxes)
+            .ok()?;
+        if number < first {
+            continue;
+        }
+        let regions: Vec<_> = regions
+            .into_iter()
+            .map(|(style, text)| (style, text.trim_end_matches(['\r', '\n'])))
+            .collect();
+        let text = format!(
+            "{:>5} {}",
+            number,
+            styled_line_to_highlighted_html(&regions, IncludeBackground::No).ok()?
+        );
+        if number == line {
+            snippet += &format!("<mark>{}</mark>\n", text);
+        } else {
+            snippet += &format!("{}\n", text);
+        }
+    }
+    Some(snippet)
+}
+
+/// Write the HTML report to a file that is kept around, and open it when run interactively
+fn open_html_report(
+    report: &Report,
+    verbosity: ReportVerbosity,
+    sort: ReportSort,
+    source_root: &Path,
+) -> Result<()> {
+    use std::io::{IsTerminal, Write};
+
+    let (file, path) = tempfile::Builder::new()
+        .prefix("flaker-report-")
+        .suffix(".html")
+        .tempfile()
+        .and_then(|tmp| tmp.keep().map_err(|err| err.error))
+        .wrap_err("Failed to create a file for the HTML report")?;
+    let mut out = std::io::BufWriter::new(file);
+    write_html_report(&mut out, report, verbosity, sort, source_root)?;
+    out.flush()?;
+    if !std::io::stdout().is_terminal() {
+        tracing::info!(path = %path.display(), "Wrote the HTML report, not opening it outside a terminal");
+        return Ok(());
+    }
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    match std::process::Command::new(opener)
+        .arg(&path)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+    {
+        Ok(status) if status.success() => {
+            tracing::info!(path = %path.display(), "Opened the HTML report")
+        }
+        Ok(status) => {
+            tracing::warn!(path = %path.display(), %status, opener, "Failed to open the HTML report")
+        }
+        Err(err) => {
+            tracing::warn!(path = %path.display(), %err, opener, "Failed to open the HTML report")
+        }
+    }
+    Ok(())
+}
+
+fn write_html_report(
+    out: &mut impl std::io::Write,
+    report: &Report,
+    verbosity: ReportVerbosity,
+    sort: ReportSort,
+    source_root: &Path,
+) -> std::io::Result<()> {
+    // Snippets only make sense for a single checkout, whose files are at hand
+    let with_sources = matches!(verbosity, Detailed) && report.stdout.len() == 1;
+    // (anchor, position, rendered lines)
+    let mut snippets: Vec<(String, &Position, String)> = vec![];
+
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(
+        out,
+        r#"<html><head><meta charset="utf-8"><title>flaker report</title>"#
+    )?;
+    writeln!(
+        out,
+        "<style>mark {{ display: inline-block; width: 100%; }}</style>"
+    )?;
+    writeln!(out, "</head><body>")?;
+    for (repo, out_diffs) in sorted(&report.stdout) {
+        if !out_diffs.is_empty() {
+            writeln!(
+                out,
+                "<p>{}: {} stdout diffs</p>",
+                escape_xml(repo),
+                out_diffs.len()
+            )?;
+        }
+    }
+    for (category, log) in report.categories() {
+        if log.is_empty() {
+            continue;
+        }
+        writeln!(out, "<h2>{}</h2>", category)?;
+        for (msg, repo_info) in report.sorted_messages(log, sort) {
+            writeln!(out, "<h3><code>{}</code></h3>", escape_xml(msg))?;
+            writeln!(out, "<ul>")?;
+            for (repo, diffs) in sorted(repo_info) {
+                let counts = report.position_counts(msg, repo, diffs);
+                if !with_sources {
+                    writeln!(
+                        out,
+                        "<li>{}: {} {}, {} {}</li>",
+                        escape_xml(repo),
+                        escape_xml(&report.labels.a),
+                        counts.result_a,
+                        escape_xml(&report.labels.b),
+                        counts.result_b
+                    )?;
+                    continue;
+                }
+                for (side, positions) in report.labels.sides(sorted_positions(diffs)) {
+                    let links: Vec<String> = positions
+                        .into_iter()
+                        .map(|pos| match html_snippet(pos, source_root) {
+                            Some(snippet) => {
+                                let id = format!("src-{}", snippets.len());
+                                let link = format!(
+                                    r##"<a href="#{}">{}</a>"##,
+                                    id,
+                                    escape_xml(&pos.to_string())
+                                );
+                                snippets.push((id, pos, snippet));
+                                link
+                            }
+                            None => escape_xml(&pos.to_string()),
+                        })
+                        .collect();
+                    writeln!(out, "<li>{}: {}</li>", escape_xml(side), links.join(", "))?;
+                }
+            }
+            writeln!(out, "</ul>")?;
+        }
+    }
+    if !snippets.is_empty() {
+        writeln!(out, "<h2>Sources</h2>")?;
+    }
+    for (id, pos, lines) in snippets {
+        writeln!(
+            out,
+            r#"<h4 id="{}">{}</h4><pre>{}</pre>"#,
+            id,
+            escape_xml(&pos.to_string()),
+            lines
+        )?;
+    }
+    writeln!(out, "</body></html>")
+}
+
+struct TreeNode {
+    label: String,
+    children: Vec<TreeNode>,
+}
+
+impl TreeNode {
+    fn leaf(label: String) -> TreeNode {
+        TreeNode {
+            label,
+            children: vec![],
+        }
+    }
+}
+
+/// Cut off a line at `width` characters, marking the cut with an ellipsis
+fn truncate(line: String, width: usize) -> String {
+    if line.chars().count() <= width {
+        return line;
+    }
+    let mut line: String = line.chars().take(width.saturating_sub(1)).collect();
+    line.push('…');
+    line
+}
+
+fn render_tree(children: &[TreeNode], prefix: &str, width: usize) {
+    for (i, child) in children.iter().enumerate() {
+        let last = i + 1 == children.len();
+        let (branch, continuation) = if last {
+            ("└─ ", "   ")
+        } else {
+            ("├─ ", "│  ")
+        };
+        let label = child.label.replace('\n', "␤");
+        println!(
+            "{}",
+            truncate(format!("{}{}{}", prefix, branch, label), width)
+        );
+        render_tree(
+            &child.children,
+            &format!("{}{}", prefix, continuation),
+            width,
+        );
+    }
+}
+
+fn print_terminal_tree(report: &Report, verbosity: ReportVerbosity, sort: ReportSort) {
+    // Don't truncate when writing into a pipe or file
+    let width = terminal_size::terminal_size()
+        .map_or(usize::MAX, |(terminal_size::Width(w), _)| w as usize);
+
+    let mut sections = vec![];
+    let stdout: Vec<TreeNode> = sorted(&report.stdout)
+        .into_iter()
+        .filter(|(_, out_diffs)| !out_diffs.is_empty())
+        .map(|(repo, out_diffs)| TreeNode::leaf(format!("{}: {}", repo, out_diffs.len())))
+        .collect();
+    if !stdout.is_empty() {
+        sections.push(TreeNode {
+            label: "Stdout diffs".into(),
+            children: stdout,
+        });
+    }
+
+    for (description, log) in [
+        ("Error Messages", &report.err_log),
+        ("Warn Messages", &report.wrn_log),
+        ("Trace Messages", &report.trc_log),
+        ("Eval Results", &report.res_log),
+    ] {
+        let messages: Vec<TreeNode> = report
+            .sorted_messages(log, sort)
+            .into_iter()
+            .map(|(msg, repo_info)| match verbosity {
+                Summary => {
+                    let (a, b) = repo_info.iter().fold((0, 0), |(a, b), (repo, diffs)| {
+                        let counts = report.position_counts(msg, repo, diffs);
+                        (a + counts.result_a, b + counts.result_b)
+                    });
+                    TreeNode::leaf(format!(
+                        "`{}`: {} repos, {}: {} {}: {}",
+                        msg,
+                        repo_info.len(),
+                        report.labels.a,
+                        a,
+                        report.labels.b,
+                        b
+                    ))
+                }
+                _ => TreeNode {
+                    label: format!("`{}`", msg),
+                    children: sorted(repo_info)
+                        .into_iter()
+                        .map(|(repo, diffs)| {
+                            let diffs = sorted_positions(diffs);
+                            TreeNode {
+                                label: repo.clone(),
+                                children: report
+                                    .labels
+                                    .sides(diffs)
+                                    .into_iter()
+                                    .flat_map(|(side, positions)| {
+                                        positions.into_iter().map(move |pos| {
+                                            TreeNode::leaf(format!("{}: {}", side, pos))
+                                        })
+                                    })
+                                    .collect(),
+                            }
+                        })
+                        .collect(),
+                },
+            })
+            .collect();
+        if !messages.is_empty() {
+            sections.push(TreeNode {
+                label: description.into(),
+                children: messages,
+            });
+        }
+    }
+
+    for section in sections {
+        println!("{}", truncate(section.label, width));
+        render_tree(&section.children, "", width);
+    }
+}
+
+/// The first position of a message in any repo, with the label of its side
+fn example_position<'a>(
+    report: &'a Report,
+    repo_info: &'a RepoPositions,
+) -> Option<(&'a str, &'a String, &'a Position)> {
+    sorted(repo_info).into_iter().find_map(|(repo, diffs)| {
+        report
+            .labels
+            .sides(sorted_positions(diffs))
+            .into_iter()
+            .find_map(|(side, positions)| Some((side, repo, positions.into_iter().next()?)))
+    })
+}
+
+/// Per repo, how many distinct messages of a category differ on each side, next to how many
+/// positions they occur at: a few new kinds of warning and many new occurrences of one are
+/// different stories
+fn print_totals(report: &Report, log: &MessageAnalysis) {
+    let mut totals: BTreeMap<&String, (Diff<usize>, Diff<usize>)> = BTreeMap::new();
+    for (msg, repo_info) in log {
+        for (repo, diffs) in repo_info {
+            let counts = report.position_counts(msg, repo, diffs);
+            let (messages, positions) = totals.entry(repo).or_default();
+            messages.result_a += usize::from(counts.result_a > 0);
+            messages.result_b += usize::from(counts.result_b > 0);
+            positions.result_a += counts.result_a;
+            positions.result_b += counts.result_b;
+        }
+    }
+    if totals.is_empty() {
+        return;
+    }
+    tracing::info!("\t|- Totals:");
+    let Labels { a, b } = &report.labels;
+    for (repo, (messages, positions)) in totals {
+        tracing::info!(
+            "\t|\t|- {}: messages {}: {} {}: {}, positions {}: {} {}: {}",
+            repo,
+            a,
+            messages.result_a,
+            b,
+            messages.result_b,
+            a,
+            positions.result_a,
+            b,
+            positions.result_b
+        );
+    }
+}
+
+fn print_report(
+    report: &Report,
+    verbosity: ReportVerbosity,
+    categories: CategoryVerbosity,
+    sort: ReportSort,
+    examples: usize,
+) {
     if report.stdout.iter().any(|(_, d)| !d.is_empty()) {
         tracing::warn!("Actual passing output differed between parsers!");
         tracing::info!("Stdout diffs:");
     }
-    for (repo, out_diffs) in report.stdout {
+    for (repo, out_diffs) in sorted(&report.stdout) {
         if out_diffs.is_empty() {
             continue;
         }
@@ -91,29 +1136,207 @@ fn print_report(report: Report, verbosity: ReportVerbosity) {
         tracing::info!("\t|- \"{}\": {}", repo, content);
     }
 
-    let print_log_report = |description: &str, log: MessageAnalysis| {
+    if !report.ast.is_empty() {
+        tracing::info!("Stdout AST Differences:");
+    }
+    for (repo, files) in sorted(&report.ast) {
+        let content = match verbosity {
+            Summary => format!(
+                "{}",
+                files
+                    .iter()
+                    .map(|(_, changes)| changes.len())
+                    .sum::<usize>()
+            ),
+            Detailed => files
+                .iter()
+                .map(|(file, changes)| {
+                    let changes: String = changes
+                        .iter()
+                        .map(
+                            |change| match (&change.node.result_a, &change.node.result_b) {
+                                (Some(a), Some(b)) => {
+                                    format!("\n\t|\t|\t|- ~ {}: `{}` -> `{}`", change.path, a, b)
+                                }
+                                (Some(a), None) => {
+                                    format!("\n\t|\t|\t|- - {}: `{}`", change.path, a)
+                                }
+                                (None, Some(b)) => {
+                                    format!("\n\t|\t|\t|- + {}: `{}`", change.path, b)
+                                }
+                                (None, None) => String::new(),
+                            },
+                        )
+                        .collect();
+                    format!("\n\t|\t|- {}:{}", file.display(), changes)
+                })
+                .collect(),
+            _ => unreachable!(),
+        };
+        tracing::info!("\t|- \"{}\": {}", repo, content);
+    }
+
+    // New and removed diagnostics first, they are what matters most
+    for (side, msgs) in report.labels.sides(report.one_sided()) {
+        if msgs.is_empty() {
+            continue;
+        }
+        tracing::info!("Only Emitted by {}:", side);
+        for msg in msgs {
+            tracing::info!("\t|- `{}`", msg);
+        }
+    }
+
+    let print_log_report = |description: &str, log: &MessageAnalysis, verbosity| {
         if log.iter().any(|(_, d)| !d.is_empty()) {
             tracing::info!("{}", description);
         }
-        for (msg, repo_info) in log {
+        for (i, (msg, repo_info)) in report.sorted_messages(log, sort).into_iter().enumerate() {
             tracing::info!("\t|- `{}`:", msg);
-            for (repo, diffs) in repo_info {
+            if let (Detailed, Some(raw)) = (verbosity, report.raw_messages.get(msg)) {
+                tracing::info!(
+                    "\t|\t|- originally: {:#?}",
+                    raw.iter().collect::<BTreeSet<_>>()
+                );
+            }
+            if let (Summary, true) = (verbosity, i < examples) {
+                if let Some(raw) = report
+                    .raw_messages
+                    .get(msg)
+                    .and_then(|raw| raw.iter().min())
+                {
+                    tracing::info!("\t|\t|- originally: `{}`", raw);
+                }
+                if let Some((side, repo, pos)) = example_position(report, repo_info) {
+                    tracing::info!("\t|\t|- e.g. {} in {}: {}", side, repo, pos);
+                }
+            }
+            for (repo, diffs) in sorted(repo_info) {
                 let content = match verbosity {
-                    Summary => format!("a: {} b: {}", diffs.result_a.len(), diffs.result_b.len()),
-                    Detailed => format!("{:#?}", diffs),
+                    Summary => {
+                        let counts = report.position_counts(msg, repo, diffs);
+                        format!(
+                            "{}: {} {}: {}",
+                            report.labels.a, counts.result_a, report.labels.b, counts.result_b
+                        )
+                    }
+                    Detailed => report
+                        .labels
+                        .sides(sorted_positions(diffs))
+                        .map(|(side, positions)| format!("{}: {:#?}", side, positions))
+                        .join("\n"),
                     _ => unreachable!(),
                 };
                 tracing::info!("\t|\t|- {}: {}", repo, content);
             }
         }
+        if let Summary = verbosity {
+            print_totals(report, log);
+        }
     };
 
-    print_log_report("Error Messages:", report.err_log);
-    print_log_report("Warn Messages:", report.wrn_log);
-    print_log_report("Trace Messages", report.trc_log);
+    print_log_report("Error Messages:", &report.err_log, categories.err);
+    print_log_report("Warn Messages:", &report.wrn_log, categories.wrn);
+    print_log_report("Trace Messages", &report.trc_log, categories.trc);
+    print_log_report("Eval Results:", &report.res_log, categories.res);
+
+    if !report.reworded.is_empty() {
+        tracing::info!("Reworded Messages:");
+    }
+    for (repo, pairs) in sorted(&report.reworded) {
+        let mut pairs: Vec<_> = pairs
+            .iter()
+            .map(|pair| (&pair.result_a, &pair.result_b))
+            .collect();
+        pairs.sort();
+        let content = match verbosity {
+            Summary => format!("{}", pairs.len()),
+            Detailed => pairs
+                .iter()
+                .map(|(a, b)| format!("\n\t|\t|- `{}` -> `{}`", a, b))
+                .collect(),
+            _ => unreachable!(),
+        };
+        tracing::info!("\t|- {}: {}", repo, content);
+    }
+
+    if !report.perf.is_empty() {
+        tracing::info!("Parse Time Differences:");
+    }
+    for (repo, files) in sorted(&report.perf) {
+        let content = match verbosity {
+            Summary => format!("{}", files.len()),
+            Detailed => files
+                .iter()
+                .map(|(file, times)| {
+                    format!(
+                        "\n\t|\t|- {}: {}: {:.2?} {}: {:.2?}",
+                        file.display(),
+                        report.labels.a,
+                        times.result_a,
+                        report.labels.b,
+                        times.result_b
+                    )
+                })
+                .collect(),
+            _ => unreachable!(),
+        };
+        tracing::info!("\t|- {}: {}", repo, content);
+    }
+
+    let mut by_class: BTreeMap<FileClass, usize> = BTreeMap::new();
+    for (file, _) in report
+        .file_outcomes
+        .values()
+        .flatten()
+        .filter(|(_, outcome)| *outcome == FileOutcome::Diff)
+    {
+        *by_class.entry(FileClass::of(file)).or_default() += 1;
+    }
+    if !by_class.is_empty() {
+        tracing::info!("Diverging Files by Class:");
+    }
+    for (class, count) in by_class {
+        tracing::info!("\t|- {}: {}", class.as_str(), count);
+    }
+
+    let failures = |outcomes: &[(PathBuf, FileOutcome)]| {
+        outcomes
+            .iter()
+            .filter(|(_, outcome)| *outcome != FileOutcome::Diff)
+            .map(|(file, outcome)| format!("{}: {:?}", file.display(), outcome))
+            .collect::<Vec<_>>()
+    };
+    if report
+        .file_outcomes
+        .values()
+        .any(|outcomes| !failures(outcomes).is_empty())
+    {
+        tracing::warn!("Some files could not be diffed:");
+    }
+    for (repo, outcomes) in sorted(&report.file_outcomes) {
+        let failures = failures(outcomes);
+        if failures.is_empty() {
+            continue;
+        }
+        let content = match verbosity {
+            Summary => format!("{}", failures.len()),
+            Detailed => format!("{:#?}", failures),
+            _ => unreachable!(),
+        };
+        tracing::info!("\t|- {}: {}", repo, content);
+    }
 }
 
-pub fn report(reports: Vec<PathBuf>, verbosity: ReportVerbosity) -> Result<()> {
+pub fn report(reports: Vec<PathBuf>, options: &ReportOptions) -> Result<Report> {
+    let verbosity = ReportVerbosity::from_str(options.verbosity.as_str())
+        .map_err(|()| eyre!("Invalid verbosity '{}'", options.verbosity))?;
+    let layout = ReportLayout::from_str(options.layout.as_str())
+        .map_err(|()| eyre!("Invalid layout '{}'", options.layout))?;
+    let format = ReportFormat::from_str(options.format.as_str())
+        .map_err(|()| eyre!("Invalid format '{}'", options.format))?;
+    let sort = ReportSort::from_str(options.sort_by.as_str())
+        .map_err(|()| eyre!("Invalid sort order '{}'", options.sort_by))?;
     let verbosity = match verbosity {
         Auto => {
             if reports.len() == 1 {
@@ -124,28 +1347,365 @@ pub fn report(reports: Vec<PathBuf>, verbosity: ReportVerbosity) -> Result<()> {
         }
         v => v,
     };
+    let categories = CategoryVerbosity::from_options(options, verbosity)?;
+    if options.open && !matches!(format, ReportFormat::Html) {
+        return Err(eyre!("`--open` only works with `--format html`"));
+    }
+
+    let (mut report, skipped) = if options.keep_going {
+        load_report_skipping_errors(&reports)
+    } else {
+        (load_report(&reports)?, vec![])
+    };
+    if let Some(path) = &options.severity_rules {
+        report.apply_severity_rules(&SeverityRules::from_path(path)?);
+    }
+    let excludes = exclude_patterns(options)?;
+    if !excludes.is_empty() {
+        let dropped = report.drop_excluded_messages(&excludes);
+        tracing::info!(dropped, "Left out excluded messages");
+    }
+    if let Some(min) = options.min_occurrences {
+        let dropped = report.drop_rare_messages(min);
+        tracing::info!(dropped, min, "Left out messages with few positions");
+    }
+
+    if options
+        .output_file
+        .as_deref()
+        .is_some_and(crate::progress::is_stdout)
+    {
+        tracing::debug!("Writing the report to stdout, not printing it");
+    } else if options.diff_only && !report.has_diffs() {
+        tracing::debug!("No differences, not printing the report");
+    } else if options.count_only {
+        println!("{}", report.stats());
+    } else if let Some(n) = options.top {
+        print_top_messages(&report, n);
+    } else {
+        match (format, layout) {
+            (ReportFormat::Junit, _) => print_junit_report(&report, sort),
+            (ReportFormat::TerminalTree, _) => print_terminal_tree(&report, verbosity, sort),
+            (ReportFormat::Html, _) => {
+                let source_root = options.source_root.as_deref().unwrap_or(Path::new("."));
+                if options.open {
+                    open_html_report(&report, verbosity, sort, source_root)?;
+                } else {
+                    write_html_report(
+                        &mut std::io::stdout().lock(),
+                        &report,
+                        verbosity,
+                        sort,
+                        source_root,
+                    )?;
+                }
+            }
+            (ReportFormat::Text, ReportLayout::Tree) => {
+                print_report(&report, verbosity, categories, sort, options.examples)
+            }
+            (ReportFormat::Text, ReportLayout::Flat) => print_flat_report(&report, sort),
+        }
+    }
+
+    if !skipped.is_empty() {
+        tracing::warn!(
+            "Skipped {} result files that could not be loaded:",
+            skipped.len()
+        );
+    }
+    for (path, err) in &skipped {
+        tracing::warn!("\t|- {}: {:#}", path.display(), err.root_cause());
+    }
+
+    Ok(report)
+}
+
+/// Load either an aggregated [`Report`] or a single [`DiffResult`], named by its file stem
+fn load_report_or_diff(path: &PathBuf) -> Result<Report> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let value: serde_json::Value = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    if value.get("err_log").is_some() {
+        Ok(serde_json::from_value(value)?)
+    } else {
+        load_report(std::slice::from_ref(path))
+    }
+}
+
+/// Show which divergences appeared, disappeared or changed in number between two runs
+pub fn report_diff(old: PathBuf, new: PathBuf) -> Result<()> {
+    let old = load_report_or_diff(&old)?;
+    let new = load_report_or_diff(&new)?;
+
+    // Including the positions left out of the sample, which may differ between the runs
+    let count = |report: &Report, log: &MessageAnalysis, msg: &Message, repo: &String| {
+        log.get(msg).and_then(|repos| repos.get(repo)).map(|diffs| {
+            let counts = report.position_counts(msg, repo, diffs);
+            counts.result_a + counts.result_b
+        })
+    };
+
+    let mut unchanged = true;
+    for ((category, old_log), (_, new_log)) in old.categories().into_iter().zip(new.categories()) {
+        let keys: BTreeSet<(&Message, &String)> = [old_log, new_log]
+            .into_iter()
+            .flat_map(|log| {
+                log.iter()
+                    .flat_map(|(msg, repos)| repos.keys().map(move |repo| (msg, repo)))
+            })
+            .collect();
+        for (msg, repo) in keys {
+            match (
+                count(&old, old_log, msg, repo),
+                count(&new, new_log, msg, repo),
+            ) {
+                (None, Some(n)) => tracing::info!("+ {} `{}` in {}: {}", category, msg, repo, n),
+                (Some(n), None) => tracing::info!("- {} `{}` in {}: {}", category, msg, repo, n),
+                (Some(o), Some(n)) if o != n => {
+                    tracing::info!("~ {} `{}` in {}: {} -> {}", category, msg, repo, o, n)
+                }
+                _ => continue,
+            }
+            unchanged = false;
+        }
+    }
+    if unchanged {
+        tracing::info!("No changes between the two runs");
+    }
+    Ok(())
+}
 
-    let diffs: HashMap<String, Result<DiffResult>> = reports
+/// Send a result to a collector service. Failures are only logged, as the result has been
+/// written locally already.
+pub async fn post_results<T: Serialize>(
+    url: &url::Url,
+    auth_header: Option<&str>,
+    result: &T,
+) -> Result<()> {
+    let mut request = crate::indexing::build_client()?
+        .post(url.clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(result)?);
+    if let Some(auth_header) = auth_header {
+        request = request.header(reqwest::header::AUTHORIZATION, auth_header);
+    }
+    match request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+    {
+        Ok(_) => tracing::info!(%url, "Posted results"),
+        Err(err) => tracing::warn!(%url, err = %err, "Failed to post results"),
+    }
+    Ok(())
+}
+
+/// Aggregate the diff results at the given paths, named by their file stem (see [`repo_names`])
+pub(crate) fn load_report(reports: &[PathBuf]) -> Result<Report> {
+    let (report, skipped) = load_report_skipping_errors(reports);
+    match skipped.into_iter().next() {
+        Some((_, err)) => Err(err),
+        None => Ok(report),
+    }
+}
+
+/// Name each result after its file stem. Results from different directories that share a stem get
+/// as many parent directories prepended as it takes to tell them apart, e.g. `a/foo` and `b/foo`.
+fn repo_names(reports: &[&PathBuf]) -> Vec<String> {
+    let stems: Vec<Vec<String>> = reports
         .iter()
         .map(|path| {
+            path.with_extension("")
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .collect()
+        })
+        .collect();
+    let name = |i: usize, depth: usize| -> String {
+        let components = &stems[i];
+        if depth > components.len() {
+            // Only the extension differs, so nothing short of the full path is unique
+            reports[i].display().to_string()
+        } else {
+            components[components.len() - depth..].join("/")
+        }
+    };
+
+    let mut depths = vec![1; reports.len()];
+    loop {
+        let mut by_name: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, &depth) in depths.iter().enumerate() {
+            by_name.entry(name(i, depth)).or_default().push(i);
+        }
+        let mut progress = false;
+        for (repo_name, indices) in by_name {
+            if indices.len() < 2 {
+                continue;
+            }
+            tracing::debug!(
+                repo = %repo_name,
+                paths = ?indices.iter().map(|&i| reports[i]).collect::<Vec<_>>(),
+                "Results share a name, disambiguating them by their directory"
+            );
+            for i in indices {
+                if depths[i] <= stems[i].len() {
+                    depths[i] += 1;
+                    progress = true;
+                }
+            }
+        }
+        if !progress {
+            break;
+        }
+    }
+
+    let names: Vec<String> = (0..reports.len()).map(|i| name(i, depths[i])).collect();
+    for (path, (repo_name, depth)) in reports.iter().zip(names.iter().zip(&depths)) {
+        if *depth > 1 {
+            tracing::warn!(path = %path.display(), repo = %repo_name, "Result file name is not unique, naming it after its directory");
+        }
+    }
+    names
+}
+
+/// Like [`load_report`], but leave out the results that can't be loaded, and return them instead
+fn load_report_skipping_errors(
+    reports: &[PathBuf],
+) -> (Report, Vec<(PathBuf, color_eyre::Report)>) {
+    let mut unique = HashSet::new();
+    let reports: Vec<&PathBuf> = reports
+        .iter()
+        .filter(|path| {
+            let first = unique.insert(*path);
+            if !first {
+                tracing::warn!(path = %path.display(), "Result given more than once, loading it once");
+            }
+            first
+        })
+        .collect();
+    let diffs: HashMap<String, (&PathBuf, Result<DiffResult>)> = repo_names(&reports)
+        .into_iter()
+        .zip(reports)
+        .map(|(repo_name, path)| {
             (
-                path.file_stem()
-                    .unwrap()
-                    .to_os_string()
-                    .into_string()
-                    .unwrap(),
-                DiffResult::from_path(path),
+                repo_name,
+                (
+                    path,
+                    DiffResult::from_path(path)
+                        .with_context(|| format!("Failed to load {}", path.display())),
+                ),
             )
         })
         .collect();
 
     let mut report = Report::default();
+    let mut labels = HashSet::new();
+    let mut skipped = vec![];
 
-    for (repo_name, diff_result) in diffs {
-        report.add(diff_result?, repo_name);
+    for (repo_name, (path, diff_result)) in diffs {
+        let diff_result = match diff_result {
+            Ok(diff_result) => diff_result,
+            Err(err) => {
+                skipped.push((path.clone(), err));
+                continue;
+            }
+        };
+        labels.insert(diff_result.labels.clone());
+        report.add(diff_result, repo_name);
+    }
+    if labels.len() > 1 {
+        tracing::warn!(
+            ?labels,
+            "Results compare differently named parsers, using `a` and `b`"
+        );
+    } else if let Some(labels) = labels.into_iter().next() {
+        report.labels = labels;
     }
+    skipped.sort_by(|a, b| a.0.cmp(&b.0));
 
-    print_report(report, verbosity);
+    (report, skipped)
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_snippet_highlights_and_marks_the_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = "let\n  s = ''\n    <b>\n\n\n\n  '';\nin s\n";
+        std::fs::write(dir.path().join("a.nix"), source).unwrap();
+        let pos = Position::from("a.nix:6".to_string());
+        let snippet = html_snippet(&pos, dir.path()).unwrap();
+        let lines: Vec<&str> = snippet.lines().collect();
+        // Up to three lines around it, as far as the file goes
+        assert_eq!(lines.len(), 6);
+        assert!(lines[0].starts_with("    3 <span"));
+        assert!(lines[0].contains("&lt;b&gt;"));
+        assert!(lines[3].starts_with("<mark>    6 "));
+        // Still within the string that started before the snippet
+        let string_style = &lines[0][lines[0].find("style").unwrap()..lines[0].find('>').unwrap()];
+        assert!(lines[4].contains(string_style));
+        assert!(!lines[5].contains(string_style));
+
+        assert!(html_snippet(&Position::from("a.nix:20".to_string()), dir.path()).is_none());
+        assert!(html_snippet(&Position::from("b.nix:1".to_string()), dir.path()).is_none());
+    }
+
+    fn positions(positions: &[&str]) -> HashSet<Position> {
+        positions
+            .iter()
+            .map(|pos| Position::from(pos.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn sort_by_count_includes_truncated_positions() {
+        let mut report = Report::default();
+        let big = Message::from("sampled");
+        let small = Message::from("complete");
+        report.wrn_log.insert(
+            big.clone(),
+            HashMap::from([(
+                "repo".to_string(),
+                Diff {
+                    result_a: positions(&["a.nix:1"]),
+                    result_b: HashSet::new(),
+                },
+            )]),
+        );
+        report.wrn_log.insert(
+            small.clone(),
+            HashMap::from([(
+                "repo".to_string(),
+                Diff {
+                    result_a: positions(&["a.nix:1", "a.nix:2", "a.nix:3"]),
+                    result_b: HashSet::new(),
+                },
+            )]),
+        );
+        report.truncated.insert(
+            "repo".to_string(),
+            HashMap::from([(
+                big.clone(),
+                Diff {
+                    result_a: 50,
+                    result_b: 0,
+                },
+            )]),
+        );
+        let order: Vec<&Message> = report
+            .sorted_messages(&report.wrn_log, ReportSort::Count)
+            .into_iter()
+            .map(|(msg, _)| msg)
+            .collect();
+        assert_eq!(order, [&big, &small]);
+        let order: Vec<&Message> = report
+            .sorted_messages(&report.wrn_log, ReportSort::Name)
+            .into_iter()
+            .map(|(msg, _)| msg)
+            .collect();
+        assert_eq!(order, [&small, &big]);
+    }
 }
@@ -0,0 +1,47 @@
+use crate::diffing::DiffResult;
+use crate::reporting::Report;
+use schemars::{schema_for, JsonSchema};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Which of flaker's output formats to describe
+#[derive(Debug, Clone, Copy)]
+pub enum SchemaKind {
+    /// The index written by `build-index`
+    Index,
+    /// The result of a single `nix-parse` run
+    Diff,
+    /// The aggregated report over multiple `nix-parse` results
+    Report,
+}
+
+impl FromStr for SchemaKind {
+    type Err = ();
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "index" => Ok(SchemaKind::Index),
+            "diff" => Ok(SchemaKind::Diff),
+            "report" => Ok(SchemaKind::Report),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Mirror of the layout `npins::NixPins::to_value_versioned` produces.
+/// The individual pins are npins' business, so they are left opaque.
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+struct Index {
+    version: u64,
+    pins: HashMap<String, serde_json::Value>,
+}
+
+pub fn print_schema(kind: SchemaKind) -> color_eyre::Result<()> {
+    let schema = match kind {
+        SchemaKind::Index => schema_for!(Index),
+        SchemaKind::Diff => schema_for!(DiffResult),
+        SchemaKind::Report => schema_for!(Report),
+    };
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}